@@ -0,0 +1,239 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+
+use crate::{
+    noise::{TerrainNoise, TerrainNormalization},
+    regions::TerrainRegions,
+    seed::TerrainSeed,
+};
+
+/// Biome id for each sample of a chunk, indexes into [`ClimateBiomes::biomes`]
+pub type BiomeMap = Vec<Vec<u8>>;
+
+/// Biome ids of a chunk, added to chunk entities when biomes are enabled
+#[derive(Component, Clone, Default)]
+pub struct TerrainBiomeMap(pub BiomeMap);
+
+/// Colors the terrain by biome instead of only by height
+#[derive(Clone, Default, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum TerrainBiomes {
+    #[default]
+    None,
+    Climate(ClimateBiomes),
+}
+
+/// Picks biomes from temperature and moisture, like a Whittaker diagram
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct ClimateBiomes {
+    pub temperature: TerrainNoise,
+    pub moisture: TerrainNoise,
+    /// How much colder it gets with height, temperature drop at a height of 1.0
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub lapse_rate: f32,
+    /// The first biome containing the temperature and moisture is used
+    pub biomes: Vec<TerrainBiome>,
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub struct TerrainBiome {
+    pub name: String,
+    /// Temperature range, min and max in 0.0 - 1.0
+    pub temperature: Vec2,
+    /// Moisture range, min and max in 0.0 - 1.0
+    pub moisture: Vec2,
+    /// Colors used for this biome
+    pub regions: TerrainRegions,
+}
+
+impl Default for ClimateBiomes {
+    fn default() -> Self {
+        let climate_noise = |seed: TerrainSeed| TerrainNoise {
+            scale: 2000.0,
+            seed,
+            // use the full range, so every biome shows up
+            normalization: TerrainNormalization::Estimated(default()),
+            ..default()
+        };
+
+        Self {
+            temperature: climate_noise(TerrainSeed::default().derive("temperature")),
+            moisture: climate_noise(TerrainSeed::default().derive("moisture")),
+            lapse_rate: 0.4,
+            biomes: vec![
+                TerrainBiome::new(
+                    "Tundra",
+                    Vec2::new(0.0, 0.25),
+                    Vec2::new(0.0, 1.0),
+                    [
+                        Color::rgb(0.1, 0.2, 0.5),
+                        Color::rgb(0.6, 0.6, 0.55),
+                        Color::rgb(0.5, 0.55, 0.45),
+                        Color::rgb(0.4, 0.45, 0.4),
+                        Color::rgb(0.55, 0.55, 0.55),
+                        Color::rgb(1.0, 1.0, 1.0),
+                    ],
+                ),
+                TerrainBiome::new(
+                    "Grassland",
+                    Vec2::new(0.25, 0.6),
+                    Vec2::new(0.0, 0.4),
+                    [
+                        Color::rgb(0.0, 0.0, 0.5),
+                        Color::rgb(0.9, 0.9, 0.5),
+                        Color::rgb(0.45, 0.65, 0.2),
+                        Color::rgb(0.35, 0.5, 0.15),
+                        Color::rgb(0.5, 0.5, 0.5),
+                        Color::rgb(1.0, 1.0, 1.0),
+                    ],
+                ),
+                TerrainBiome::new(
+                    "Temperate Forest",
+                    Vec2::new(0.25, 0.6),
+                    Vec2::new(0.4, 1.0),
+                    [
+                        Color::rgb(0.0, 0.0, 0.5),
+                        Color::rgb(0.9, 0.9, 0.5),
+                        Color::rgb(0.0, 0.5, 0.0),
+                        Color::rgb(0.0, 0.25, 0.0),
+                        Color::rgb(0.5, 0.5, 0.5),
+                        Color::rgb(1.0, 1.0, 1.0),
+                    ],
+                ),
+                TerrainBiome::new(
+                    "Desert",
+                    Vec2::new(0.6, 1.0),
+                    Vec2::new(0.0, 0.3),
+                    [
+                        Color::rgb(0.1, 0.3, 0.5),
+                        Color::rgb(0.95, 0.85, 0.6),
+                        Color::rgb(0.9, 0.75, 0.5),
+                        Color::rgb(0.8, 0.6, 0.4),
+                        Color::rgb(0.6, 0.4, 0.3),
+                        Color::rgb(0.7, 0.5, 0.4),
+                    ],
+                ),
+                TerrainBiome::new(
+                    "Savanna",
+                    Vec2::new(0.6, 1.0),
+                    Vec2::new(0.3, 0.6),
+                    [
+                        Color::rgb(0.0, 0.2, 0.5),
+                        Color::rgb(0.9, 0.85, 0.55),
+                        Color::rgb(0.7, 0.65, 0.3),
+                        Color::rgb(0.5, 0.5, 0.2),
+                        Color::rgb(0.5, 0.45, 0.4),
+                        Color::rgb(0.9, 0.9, 0.9),
+                    ],
+                ),
+                TerrainBiome::new(
+                    "Rainforest",
+                    Vec2::new(0.6, 1.0),
+                    Vec2::new(0.6, 1.0),
+                    [
+                        Color::rgb(0.0, 0.25, 0.4),
+                        Color::rgb(0.8, 0.8, 0.5),
+                        Color::rgb(0.0, 0.4, 0.1),
+                        Color::rgb(0.0, 0.2, 0.05),
+                        Color::rgb(0.3, 0.35, 0.3),
+                        Color::rgb(0.9, 0.9, 0.9),
+                    ],
+                ),
+            ],
+        }
+    }
+}
+
+impl TerrainBiome {
+    /// Biome using the default region heights with its own colors
+    pub fn new(name: &str, temperature: Vec2, moisture: Vec2, colors: [Color; 6]) -> Self {
+        let mut regions = TerrainRegions::default();
+        for (region, color) in regions.0.iter_mut().zip(colors) {
+            region.color = color;
+        }
+        Self {
+            name: name.to_string(),
+            temperature,
+            moisture,
+            regions,
+        }
+    }
+
+    fn contains(&self, temperature: f32, moisture: f32) -> bool {
+        temperature >= self.temperature.x
+            && temperature <= self.temperature.y
+            && moisture >= self.moisture.x
+            && moisture <= self.moisture.y
+    }
+
+    /// Distance from the center of the biome in the temperature and moisture space
+    fn distance(&self, temperature: f32, moisture: f32) -> f32 {
+        let center = Vec2::new(
+            (self.temperature.x + self.temperature.y) / 2.0,
+            (self.moisture.x + self.moisture.y) / 2.0,
+        );
+        center.distance(Vec2::new(temperature, moisture))
+    }
+}
+
+impl ClimateBiomes {
+    /// Temperature at a world-space position, cooled by height when `lapse_rate` is set
+    pub fn temperature(&self, world_pos: Vec2, height: f32) -> f32 {
+        (self.temperature.get(world_pos) - self.lapse_rate * height.max(0.0)).clamp(0.0, 1.0)
+    }
+
+    pub fn moisture(&self, world_pos: Vec2) -> f32 {
+        self.moisture.get(world_pos)
+    }
+
+    /// Biome id at a world-space position, uses the closest biome if none contain the climate there
+    pub fn get(&self, world_pos: Vec2, height: f32) -> u8 {
+        let temperature = self.temperature(world_pos, height);
+        let moisture = self.moisture(world_pos);
+
+        let index = self
+            .biomes
+            .iter()
+            .position(|biome| biome.contains(temperature, moisture))
+            .or_else(|| {
+                self.biomes
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| {
+                        a.distance(temperature, moisture)
+                            .total_cmp(&b.distance(temperature, moisture))
+                    })
+                    .map(|(i, _)| i)
+            })
+            .unwrap_or(0);
+        index as u8
+    }
+
+    /// Color of a biome at a height, black if the biome doesn't exist
+    pub fn get_color(&self, biome: u8, height: f32) -> Color {
+        self.biomes
+            .get(biome as usize)
+            .map_or(Color::BLACK, |x| x.regions.get_color(height))
+    }
+
+    /// Derives the temperature and moisture seeds from a world seed
+    pub fn set_seed(&mut self, seed: TerrainSeed) {
+        self.temperature.seed = seed.derive("temperature");
+        self.moisture.seed = seed.derive("moisture");
+    }
+
+    pub fn update_normalization(&mut self) {
+        self.temperature.update_normalization();
+        self.moisture.update_normalization();
+    }
+}
+
+impl TerrainBiomes {
+    pub fn update_normalization(&mut self) {
+        if let TerrainBiomes::Climate(x) = self {
+            x.update_normalization();
+        }
+    }
+}
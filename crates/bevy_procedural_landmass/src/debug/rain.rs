@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use bevy_inspector_egui::{InspectorOptions, prelude::*};
 
-#[cfg(debug_rain)]
+#[cfg(feature = "debug_rain")]
 use crate::{TerrainChunk, util::lerp_color};
 
 pub struct TerrainDebugRainPlugin;
@@ -16,7 +16,7 @@ pub enum TerrainDebugRainMode {
 impl Plugin for TerrainDebugRainPlugin {
     fn build(&self, app: &mut App) {
         app.add_state::<TerrainDebugRainMode>();
-        #[cfg(debug_rain)]
+        #[cfg(feature = "debug_rain")]
         app.add_systems(
             Update,            
             (draw_rain).run_if(in_state(TerrainDebugRainMode::On)),
@@ -28,10 +28,10 @@ impl Plugin for TerrainDebugRainPlugin {
 #[reflect(Component, InspectorOptions)]
 pub struct RainPaths(pub Vec<Vec<Vec3>>);
 
-#[cfg(debug_rain)]
+#[cfg(feature = "debug_rain")]
 pub fn draw_rain(query: Query<(&RainPaths, &TerrainChunk)>, mut gizmos: Gizmos) {
     for (paths, _chunk) in query.iter() {
-        for path in paths.0.iter().take(10) {
+        for path in paths.0.iter().filter(|x| x.len() > 1) {
             for i in 0..path.len() - 1 {
                 // TODO: due to gismo draw order, bump points up a little to make them more visable
                 // remove once gimzo draw order is fixed
@@ -1,12 +1,22 @@
-use crate::NoiseMap;
-use bevy::prelude::*;
+use crate::{
+    pass::{BlurPass, TerrainPassContext, TerrainPassOutput},
+    seed::TerrainSeed,
+    shallow_water::ShallowWaterErosion,
+    thermal::ThermalErosion,
+    util, NoiseMap,
+};
+use bevy::{prelude::*, utils::HashMap};
 use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+use std::sync::{Arc, Mutex, OnceLock};
 
 #[derive(Clone, Reflect, InspectorOptions)]
 #[reflect(InspectorOptions)]
 pub enum TerrainErosion {
     None,
     Hydraulic(HydraulicErosion),
+    Thermal(ThermalErosion),
+    /// Grid based hydraulic erosion, also leaves a [`TerrainWaterMap`](crate::shallow_water::TerrainWaterMap)
+    ShallowWater(ShallowWaterErosion),
 }
 
 impl Default for TerrainErosion {
@@ -15,12 +25,103 @@ impl Default for TerrainErosion {
     }
 }
 
+impl TerrainErosion {
+    /// Erodes the map, adding the rain paths and water to the output
+    pub fn apply(
+        &self,
+        map: &mut NoiseMap,
+        context: &TerrainPassContext,
+        output: &mut TerrainPassOutput,
+    ) {
+        match self {
+            TerrainErosion::None => {}
+            TerrainErosion::Hydraulic(x) => {
+                let result = x.erode(map, context);
+                output.rain_paths.get_or_insert_with(Vec::new).extend(result.rain_paths);
+                if let Some(maps) = result.maps {
+                    match &mut output.erosion_maps {
+                        Some(existing) => existing.merge(&maps),
+                        None => output.erosion_maps = Some(maps),
+                    }
+                }
+            }
+            TerrainErosion::Thermal(x) => x.erode(map, context),
+            TerrainErosion::ShallowWater(x) => output.water_map = Some(x.erode(map, context)),
+        }
+    }
+
+    /// Border the erosion needs around the chunk, see [`TerrainHeightPass::padding`](crate::pass::TerrainHeightPass::padding)
+    pub fn padding(&self) -> usize {
+        match self {
+            TerrainErosion::None => 0,
+            TerrainErosion::Hydraulic(x) => x.padding(),
+            TerrainErosion::Thermal(x) => x.padding(),
+            TerrainErosion::ShallowWater(x) => x.padding(),
+        }
+    }
+}
+
+/// By-products of [`HydraulicErosion`] for every sample, added to chunk entities when
+/// [`HydraulicErosion::output_maps`] is on. Useful to paint riverbeds and alluvial fans,
+/// or to scatter vegetation in gullies.
+#[derive(Component, Clone, Default)]
+pub struct TerrainErosionMaps {
+    /// Height of the sediment deposited, in the same units as the heights
+    pub sediment: NoiseMap,
+    /// Water that flowed through, in droplet volumes
+    pub flow: NoiseMap,
+    /// Flow spread over the erosion radius and mapped to 0.0 - 1.0
+    pub wetness: NoiseMap,
+}
+
+impl TerrainErosionMaps {
+    pub fn new(size: usize) -> Self {
+        Self {
+            sediment: vec![vec![0.0; size]; size],
+            flow: vec![vec![0.0; size]; size],
+            wetness: vec![vec![0.0; size]; size],
+        }
+    }
+
+    /// Adds the maps of a later erosion pass
+    pub fn merge(&mut self, other: &TerrainErosionMaps) {
+        for x in 0..self.sediment.len() {
+            for y in 0..self.sediment.len() {
+                self.sediment[x][y] += other.sediment[x][y];
+                self.flow[x][y] += other.flow[x][y];
+                self.wetness[x][y] = self.wetness[x][y].max(other.wetness[x][y]);
+            }
+        }
+    }
+
+    /// Applies `f` to every map, e.g. to crop them
+    pub fn map(self, mut f: impl FnMut(NoiseMap) -> NoiseMap) -> Self {
+        Self {
+            sediment: f(self.sediment),
+            flow: f(self.flow),
+            wetness: f(self.wetness),
+        }
+    }
+}
+
+/// What [`HydraulicErosion::erode`] gives besides the new heights
+#[derive(Default)]
+pub struct HydraulicErosionOutput {
+    pub rain_paths: Vec<Vec<Vec3>>,
+    pub maps: Option<TerrainErosionMaps>,
+}
+
 // Based on https://github.com/SebLague/Hydraulic-Erosion/blob/master/Assets/Scripts/Erosion.cs
 #[derive(Clone, Reflect, InspectorOptions)]
 #[reflect(InspectorOptions)]
 pub struct HydraulicErosion {
+    /// Average number of droplets per chunk
     #[inspector(min = 0, max = 100_000, display = NumberDisplay::Slider)]
     pub iterations: usize,
+    /// Droplets in a round only see the heights from the round before, so chunks can be eroded
+    /// on their own. More rounds are closer to running droplets one at a time, but need a larger border.
+    #[inspector(min = 1, max = 16, display = NumberDisplay::Slider)]
+    pub rounds: usize,
     #[inspector(min = 2, max = 8, display = NumberDisplay::Slider)]
     pub erosion_radius: usize,
     #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
@@ -37,14 +138,24 @@ pub struct HydraulicErosion {
     max_droplet_lifetime: u32,
     initial_water_volume: f32,
     initial_speed: f32,
-    pub seed: u64,
-
+    pub seed: TerrainSeed,
+    /// Keeps every sample between the lowest and highest of the samples around it after each round.
+    /// Droplets in a round all read the same heights, so with many droplets per sample they can keep
+    /// filling the same pit or digging the same peak, this stops those from turning into spikes.
+    pub clamp_to_neighbours: bool,
+    /// Also make the sediment, flow and wetness maps, see [`TerrainErosionMaps`]
+    pub output_maps: bool,
+    /// Droplets per chunk to record the paths of for [`RainPaths`](crate::debug::RainPaths),
+    /// needs the `debug_rain` feature
+    #[inspector(min = 0, max = 100, display = NumberDisplay::Slider)]
+    pub debug_rain_droplets: usize,
 }
 
 impl Default for HydraulicErosion {
     fn default() -> Self {
         Self {
             iterations: 100,
+            rounds: 4,
             erosion_radius: 3,
             inertia: 0.05,
             sediment_capacity_factor: 4.0,
@@ -56,214 +167,433 @@ impl Default for HydraulicErosion {
             max_droplet_lifetime: 10,
             initial_water_volume: 1.0,
             initial_speed: 1.0,
-            seed: 0,
+            seed: TerrainSeed::default().derive("erosion"),
+            clamp_to_neighbours: false,
+            output_maps: false,
+            debug_rain_droplets: 10,
         }
     }
 }
 
 impl HydraulicErosion {
-    pub fn erode(&self, 
-        map: &mut NoiseMap, 
-        map_size: usize, 
-        #[cfg(debug_rain)]
-        wolrd_scale: f32,
-        #[cfg(debug_rain)]
-         height_multiplyer: f32
-        
-    ) -> Vec<Vec<Vec3>> {
-        
-        let erosion_brushes = initialize_brushes(map_size, self.erosion_radius);
-        
-        // paths rain drops take, for debuging only
-        #[cfg(debug_rain)] {
-        let mut rain_paths : Vec<Vec<Vec3>> = vec![vec![]; self.iterations];        
-        let xy_rain_scale = wolrd_scale  as f32 / map_size as f32;
-        let half_size = map_size as f32 / 2.0;
+    /// Samples a droplet can reach from where it starts, the border the chunk needs for every round
+    pub fn reach(&self) -> usize {
+        self.max_droplet_lifetime as usize + self.erosion_radius + 2
     }
 
-        fastrand::seed(self.seed);
-
-        
-        for _ in 0..self.iterations {
-            
-            // Create water droplet at random point on map
-            let mut pos_x = fastrand::f32() * (map_size - 1) as f32;
-            let mut pos_y = fastrand::f32() * (map_size - 1) as f32;
-
-            let mut dir_x = 0.0;
-            let mut dir_y = 0.0;
-            let mut speed = self.initial_speed;
-            let mut water = self.initial_water_volume;
-            let mut sediment = 0.0;
-
-            #[cfg(debug_rain)]           
-            rain_paths[i].push(Vec3 {
-                x: (pos_x - half_size) * xy_rain_scale,                
-                y: (map[pos_x as usize][pos_y as usize] * wolrd_scale * height_multiplyer) + 10.0,
-                z: (pos_y - half_size) * xy_rain_scale,
-            });
-
-            for _ in 0..self.max_droplet_lifetime {                
-                let node_x = pos_x as usize;
-                let node_y = pos_y as usize;
-
-                // Calculate droplet's offset inside the cell
-                let cell_offset_x = pos_x - node_x as f32;
-                let cell_offset_y = pos_y - node_y as f32;
-
-                // Calculate droplet's height and direction of flow with bilinear interpolation of surrounding heights
-                let height_and_gradient = calculate_height_and_gradient(map, pos_x, pos_y);
-
-                // Update the droplet's direction and position
-                dir_x = dir_x * self.inertia - height_and_gradient.gradient_x * (1.0 - self.inertia);
-                dir_y = dir_y * self.inertia - height_and_gradient.gradient_y * (1.0 - self.inertia);
-                // Normalize direction
-                let len = (dir_x * dir_x + dir_y * dir_y).sqrt();
-                if len != 0.0 {
-                    dir_x /= len;
-                    dir_y /= len;
-                }
-                pos_x += dir_x;
-                pos_y += dir_y;
-                
-                #[cfg(debug_rain)]
-                rain_paths[i].push(Vec3 {
-                    x: (pos_x - half_size) * xy_rain_scale,
-                    y: map[node_x][node_y] * wolrd_scale * height_multiplyer,
-                    z: (pos_y - half_size) * xy_rain_scale,
-                });
+    /// Border needed so the chunk edges match the neighbouring chunks
+    pub fn padding(&self) -> usize {
+        self.rounds.max(1) * self.reach()
+    }
 
+    /// Erodes a padded map, the outer [`HydraulicErosion::padding`] samples are only used as input
+    ///
+    /// Droplets start in world-space cells and within a round only read the heights from the
+    /// round before, their changes are added up in world order. So the samples a chunk shares
+    /// with its neighbours end up exactly the same, whatever order the chunks are made in.
+    pub fn erode(
+        &self,
+        map: &mut NoiseMap,
+        context: &TerrainPassContext,
+    ) -> HydraulicErosionOutput {
+        if map.len() < 3 {
+            return HydraulicErosionOutput::default();
+        }
+        // droplets stay a sample away from the last row, like they did on the unpadded chunk
+        let map_size = map.len() - 1;
+
+        let erosion_brushes = ErosionBrushes::cached(map.len(), self.erosion_radius);
+
+        // paths of a few droplets starting in the chunk, for debuging only
+        let mut rain_paths: Vec<Vec<Vec3>> = vec![];
+        #[cfg(feature = "debug_rain")]
+        let rain_chance = self.debug_rain_droplets as f32 / self.iterations.max(1) as f32;
+
+        let rounds = self.rounds.max(1);
+        // average droplets per cell each round
+        let density =
+            self.iterations as f32 / (context.chunk_size * context.chunk_size * rounds) as f32;
+
+        let mut delta = vec![vec![0f32; map.len()]; map.len()];
+        let mut maps = self.output_maps.then(|| TerrainErosionMaps::new(map.len()));
+
+        for round in 0..rounds {
+            // walk the cells in world order, so every chunk adds the changes in the same order
+            for cell_y in 0..map_size - 1 {
+                for cell_x in 0..map_size - 1 {
+                    let world_cell = context.origin_texel + IVec2::new(cell_x as i32, cell_y as i32);
+                    let mut rng = self.droplet_rng(round, world_cell);
+                    // separate from the droplets, so recording them doesn't change the terrain
+                    #[cfg(feature = "debug_rain")]
+                    let mut rain_rng = fastrand::Rng::with_seed(util::hash_2d(
+                        world_cell.x,
+                        world_cell.y,
+                        self.seed.derive("debug_rain").layer(round as u64).to_u32(),
+                    ) as u64);
+                    #[cfg(feature = "debug_rain")]
+                    let in_chunk = (context.padding..context.padding + context.chunk_size)
+                        .contains(&cell_x)
+                        && (context.padding..context.padding + context.chunk_size).contains(&cell_y);
+
+                    let mut droplets = density as usize;
+                    if rng.f32() < density.fract() {
+                        droplets += 1;
+                    }
 
-                // Stop simulating droplet if it's not moving or has flowed over edge of map
-                if (dir_x == 0.0 && dir_y == 0.0)
-                    || pos_x < 0.0
-                    || pos_x >= map_size as f32 - 1.0
-                    || pos_y < 0.0
-                    || pos_y >= map_size as f32 - 1.0
-                {
-                    break;
+                    for _ in 0..droplets {
+                        let start = Vec2::new(rng.f32(), rng.f32());
+                        #[cfg(feature = "debug_rain")]
+                        let record = in_chunk && rain_rng.f32() < rain_chance;
+                        #[cfg(not(feature = "debug_rain"))]
+                        let record = false;
+
+                        let mut path = vec![];
+                        self.simulate_droplet(
+                            map,
+                            DropletChanges {
+                                delta: &mut delta,
+                                maps: maps.as_mut(),
+                                path: record.then_some(&mut path),
+                            },
+                            &erosion_brushes,
+                            (cell_x, cell_y),
+                            start,
+                        );
+                        if record {
+                            rain_paths.push(path);
+                        }
+                    }
                 }
+            }
 
-                // Find the droplet's new height and calculate the delta_height
-                let new_height = calculate_height_and_gradient(map, pos_x, pos_y).height;
-                let delta_height = new_height - height_and_gradient.height;
-
-                // Calculate the droplet's sediment capacity
-                let sediment_capacity =
-                    (-delta_height * speed * water * self.sediment_capacity_factor)
-                        .max(self.min_sediment_capacity);
-
-                // If carrying more sediment than capacity, or if flowing uphill:
-                if sediment > sediment_capacity || delta_height > 0.0 {
-                    
-                    // If moving uphill try to fill up to the current height, otherwise deposit a fraction of the excess sediment
-                    let amount_to_deposit = if delta_height > 0.0 {                        
-                        delta_height.min(sediment)
-                    } else {                        
-                        (sediment - sediment_capacity) * self.deposit_speed
-                    };
-                    sediment -= amount_to_deposit;
-
-                    // Add the sediment to the four nodes of the current cell
-                     let ammount = (
-                        amount_to_deposit * (1.0 - cell_offset_x) * (1.0 - cell_offset_y),
-                        amount_to_deposit * (1.0 - cell_offset_x) * cell_offset_y,
-                        amount_to_deposit * cell_offset_x * (1.0 - cell_offset_y),
-                        amount_to_deposit * cell_offset_x * cell_offset_y
-                     );
-
-                    map[node_x][node_y] += ammount.0;
-                    map[node_x][node_y + 1] += ammount.1;
-                    map[node_x + 1][node_y] += ammount.2;
-                    map[node_x + 1][node_y + 1] += ammount.3;
-                } else {
-                    // Erode a fraction of the droplet's current carry capacity
-                    let amount_to_erode = ((sediment_capacity - sediment) * self.erode_speed).min(-delta_height);                    
-                    // Use erosion brush to erode from all nodes inside the droplet's erosion radius
-                    for brush in erosion_brushes[node_x][node_y].iter() {
-
-                        let weighed_erode_amount = amount_to_erode * brush.weight;                        
-                        let delta_sediment = map[brush.x][brush.y].min(weighed_erode_amount);
-
-                        map[brush.x][brush.y] -= delta_sediment;
-                        sediment += delta_sediment;
+            if self.clamp_to_neighbours {
+                *map = clamp_to_neighbours(map, &delta);
+            } else {
+                for (column, delta) in map.iter_mut().zip(delta.iter()) {
+                    for (height, delta) in column.iter_mut().zip(delta) {
+                        *height += delta;
                     }
                 }
+            }
+            for delta in delta.iter_mut().flatten() {
+                *delta = 0.0;
+            }
+        }
 
-                // Update droplet's speed and water content
-                speed = (speed * speed + delta_height * self.gravity).sqrt();
-                water *= 1.0 - self.evaporate_speed;
+        if let Some(maps) = &mut maps {
+            // spread the flow so the banks are damp too
+            maps.wetness = maps.flow.clone();
+            BlurPass {
+                radius: self.erosion_radius,
+                strength: 1.0,
+            }
+            .apply(&mut maps.wetness);
+            for wetness in maps.wetness.iter_mut().flatten() {
+                *wetness = 1.0 - (-*wetness).exp();
             }
         }
-        
-            
-        
 
-        #[cfg(debug_rain)]
-        rain_paths;
+        // from samples to the world, the same as the chunk mesh
+        let origin = context.world_position(0, 0);
+        let height_scale = context.height_multiplier * context.world_scale;
+        for point in rain_paths.iter_mut().flatten() {
+            let xz = origin + point.truncate() * context.texel_size;
+            *point = Vec3::new(xz.x, point.z * height_scale, xz.y);
+        }
+
+        HydraulicErosionOutput { rain_paths, maps }
+    }
+
+    /// Random numbers for the droplets starting in a world cell in a round
+    ///
+    /// Every cell gets its own generator from the erosion seed, so chunks can be eroded
+    /// on any thread and in any order, and always get the same droplets.
+    pub fn droplet_rng(&self, round: usize, world_cell: IVec2) -> fastrand::Rng {
+        let round_seed = self.seed.layer(round as u64).to_u32();
+        fastrand::Rng::with_seed(util::hash_2d(world_cell.x, world_cell.y, round_seed) as u64)
+    }
+
+    /// Runs a droplet over `map` without changing it, what it does is written to `changes`
+    ///
+    /// The droplet's position is kept relative to the cell it starts in, so the floating point
+    /// rounding is the same wherever that cell is in the padded map.
+    fn simulate_droplet(
+        &self,
+        map: &NoiseMap,
+        changes: DropletChanges,
+        erosion_brushes: &ErosionBrushes,
+        cell: (usize, usize),
+        start: Vec2,
+    ) {
+        let DropletChanges {
+            delta,
+            mut maps,
+            mut path,
+        } = changes;
+        let map_size = map.len() - 1;
+        // position relative to the start cell
+        let (mut pos_x, mut pos_y) = (start.x, start.y);
+        let mut dir_x = 0.0;
+        let mut dir_y = 0.0;
+        let mut speed = self.initial_speed;
+        let mut water = self.initial_water_volume;
+        let mut sediment = 0.0;
+
+        let mut node_x = cell.0;
+        let mut node_y = cell.1;
+        let mut cell_offset_x = pos_x;
+        let mut cell_offset_y = pos_y;
+
+        if let Some(path) = path.as_deref_mut() {
+            let height =
+                calculate_height_and_gradient(map, node_x, node_y, cell_offset_x, cell_offset_y)
+                    .height;
+            path.push(Vec3::new(cell.0 as f32 + pos_x, cell.1 as f32 + pos_y, height));
+        }
+
+        for _ in 0..self.max_droplet_lifetime {
+            // Calculate droplet's height and direction of flow with bilinear interpolation of surrounding heights
+            let height_and_gradient =
+                calculate_height_and_gradient(map, node_x, node_y, cell_offset_x, cell_offset_y);
+
+            if let Some(maps) = maps.as_deref_mut() {
+                add_bilinear(&mut maps.flow, node_x, node_y, cell_offset_x, cell_offset_y, water);
+            }
+
+            // Update the droplet's direction and position
+            dir_x = dir_x * self.inertia - height_and_gradient.gradient_x * (1.0 - self.inertia);
+            dir_y = dir_y * self.inertia - height_and_gradient.gradient_y * (1.0 - self.inertia);
+            // Normalize direction
+            let len = (dir_x * dir_x + dir_y * dir_y).sqrt();
+            if len != 0.0 {
+                dir_x /= len;
+                dir_y /= len;
+            }
+            pos_x += dir_x;
+            pos_y += dir_y;
+
+            // Find the droplet's new cell
+            let new_node_x = cell.0 as isize + pos_x.floor() as isize;
+            let new_node_y = cell.1 as isize + pos_y.floor() as isize;
+
+            // Stop simulating droplet if it's not moving or has flowed over edge of map
+            if (dir_x == 0.0 && dir_y == 0.0)
+                || new_node_x < 0
+                || new_node_x >= map_size as isize - 1
+                || new_node_y < 0
+                || new_node_y >= map_size as isize - 1
+            {
+                break;
+            }
+            let (new_node_x, new_node_y) = (new_node_x as usize, new_node_y as usize);
+            let (new_offset_x, new_offset_y) = (pos_x - pos_x.floor(), pos_y - pos_y.floor());
+
+            // Find the droplet's new height and calculate the delta_height
+            let new_height =
+                calculate_height_and_gradient(map, new_node_x, new_node_y, new_offset_x, new_offset_y)
+                    .height;
+            let delta_height = new_height - height_and_gradient.height;
 
-        vec![]
+            if let Some(path) = path.as_deref_mut() {
+                path.push(Vec3::new(cell.0 as f32 + pos_x, cell.1 as f32 + pos_y, new_height));
+            }
+
+            // Calculate the droplet's sediment capacity
+            let sediment_capacity = (-delta_height * speed * water * self.sediment_capacity_factor)
+                .max(self.min_sediment_capacity);
+
+            // If carrying more sediment than capacity, or if flowing uphill:
+            if sediment > sediment_capacity || delta_height > 0.0 {
+                // If moving uphill try to fill up to the current height, otherwise deposit a fraction of the excess sediment
+                let amount_to_deposit = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - sediment_capacity) * self.deposit_speed
+                };
+                sediment -= amount_to_deposit;
+
+                // Add the sediment to the four nodes of the current cell
+                let ammount = (
+                    amount_to_deposit * (1.0 - cell_offset_x) * (1.0 - cell_offset_y),
+                    amount_to_deposit * (1.0 - cell_offset_x) * cell_offset_y,
+                    amount_to_deposit * cell_offset_x * (1.0 - cell_offset_y),
+                    amount_to_deposit * cell_offset_x * cell_offset_y,
+                );
+
+                delta[node_x][node_y] += ammount.0;
+                delta[node_x][node_y + 1] += ammount.1;
+                delta[node_x + 1][node_y] += ammount.2;
+                delta[node_x + 1][node_y + 1] += ammount.3;
+
+                if let Some(maps) = maps.as_deref_mut() {
+                    let (x, y) = (cell_offset_x, cell_offset_y);
+                    add_bilinear(&mut maps.sediment, node_x, node_y, x, y, amount_to_deposit);
+                }
+            } else {
+                // Erode a fraction of the droplet's current carry capacity
+                let amount_to_erode =
+                    ((sediment_capacity - sediment) * self.erode_speed).min(-delta_height);
+                // Use erosion brush to erode from all nodes inside the droplet's erosion radius
+                for brush in erosion_brushes.get(node_x, node_y) {
+                    let x = (node_x as isize + brush.x) as usize;
+                    let y = (node_y as isize + brush.y) as usize;
+                    let weighed_erode_amount = amount_to_erode * brush.weight;
+                    let delta_sediment = map[x][y].min(weighed_erode_amount);
+
+                    delta[x][y] -= delta_sediment;
+                    sediment += delta_sediment;
+                }
+            }
 
+            // Update droplet's speed and water content
+            speed = (speed * speed + delta_height * self.gravity).sqrt();
+            water *= 1.0 - self.evaporate_speed;
 
+            node_x = new_node_x;
+            node_y = new_node_y;
+            cell_offset_x = new_offset_x;
+            cell_offset_y = new_offset_y;
+        }
     }
 }
 
+/// Adds the changes of a round, keeping each sample between the lowest and highest samples around it
+fn clamp_to_neighbours(map: &NoiseMap, delta: &NoiseMap) -> NoiseMap {
+    let size = map.len();
+    let mut next = map.clone();
+    for x in 0..size {
+        for y in 0..size {
+            let (mut low, mut high) = (f32::MAX, f32::MIN);
+            for column in &map[x.saturating_sub(1)..(x + 2).min(size)] {
+                for height in &column[y.saturating_sub(1)..(y + 2).min(size)] {
+                    low = low.min(*height);
+                    high = high.max(*height);
+                }
+            }
+            next[x][y] = (map[x][y] + delta[x][y]).clamp(low, high);
+        }
+    }
+    next
+}
+
+/// Everything a droplet writes, kept apart from the heights it reads
+struct DropletChanges<'a> {
+    delta: &'a mut NoiseMap,
+    maps: Option<&'a mut TerrainErosionMaps>,
+    /// Points the droplet passes, x and y in samples and z the height
+    path: Option<&'a mut Vec<Vec3>>,
+}
 
-#[derive(Clone, Debug)]
+/// Spreads `amount` over the four nodes of a cell by the offset inside it
+fn add_bilinear(map: &mut NoiseMap, node_x: usize, node_y: usize, x: f32, y: f32, amount: f32) {
+    map[node_x][node_y] += amount * (1.0 - x) * (1.0 - y);
+    map[node_x][node_y + 1] += amount * (1.0 - x) * y;
+    map[node_x + 1][node_y] += amount * x * (1.0 - y);
+    map[node_x + 1][node_y + 1] += amount * x * y;
+}
+
+/// Offset of a sample from the droplet and its share of the erosion
+#[derive(Clone, Copy, Debug)]
 struct Brush {
-    x: usize,
-    y: usize,
+    x: isize,
+    y: isize,
     weight: f32,
 }
-fn initialize_brushes(map_size: usize, radius: usize) -> Vec<Vec<Vec<Brush>>> {
-    let mut erosion_brushs: Vec<Vec<Vec<Brush>>> = vec![vec![vec![]; map_size]; map_size];    
-
-    let mut x_offsets = vec![0; radius * radius * 4];
-    let mut y_offsets = vec![0; radius * radius * 4];
-    let mut weights = vec![0.0; radius * radius * 4];
-
-    for y in 0..map_size {
-        for x in 0..map_size {
-            if y <= radius
-                || y >= map_size - radius
-                || x <= radius + 1
-                || x >= map_size - radius
-            {
-                let mut weight_sum = 0.0;
-                let mut add_index = 0;
-
-                for by in -(radius as isize)..=radius as isize {
-                    for bx in -(radius as isize)..=radius as isize {
-                        let sqr_dst = (bx * bx + by * by) as f32;
-                        if sqr_dst < (radius * radius) as f32 {
-                            let coord_x = (bx as isize + bx) as usize;
-                            let coord_y = (by as isize + by) as usize;
-
-                            if coord_x < map_size && coord_y < map_size {
-                                let weight = 1.0 - (sqr_dst.sqrt()) / radius as f32;
-                                weight_sum += weight;
-                                weights[add_index] = weight;
-                                x_offsets[add_index] = bx;
-                                y_offsets[add_index] = by;
-                                add_index += 1;
-                            }
-                        }
-                    }
-                }
 
-                for j in 0..add_index {
-                    erosion_brushs[x][y].push(Brush { 
-                        x: (x_offsets[j] + x as isize) as usize,
-                        y: (y_offsets[j] + y as isize) as usize,
-                        weight: weights[j] / weight_sum });                    
+/// Erosion brushes for every sample of a map, the weights add up to 1.0 and are cut off at
+/// the edges of the map
+struct ErosionBrushes {
+    size: usize,
+    radius: usize,
+    /// Brush for samples at least `radius` from the edges
+    interior: Vec<Brush>,
+    /// Brushes for the samples near the edges, by `x * size + y`, empty for the interior
+    edges: Vec<Vec<Brush>>,
+}
+
+type BrushCache = Mutex<HashMap<(usize, usize), Arc<ErosionBrushes>>>;
+
+/// Brushes only depend on the map size and radius, so every chunk task shares them
+static BRUSH_CACHE: OnceLock<BrushCache> = OnceLock::new();
+
+impl ErosionBrushes {
+    fn cached(size: usize, radius: usize) -> Arc<ErosionBrushes> {
+        let mut cache = BRUSH_CACHE
+            .get_or_init(default)
+            .lock()
+            .unwrap_or_else(|x| x.into_inner());
+        // a few sizes are used at a time, drop the old ones when the settings keep changing
+        if cache.len() > 16 {
+            cache.clear();
+        }
+        cache
+            .entry((size, radius))
+            .or_insert_with(|| Arc::new(ErosionBrushes::new(size, radius)))
+            .clone()
+    }
+
+    fn new(size: usize, radius: usize) -> Self {
+        let mut brushes = Self {
+            size,
+            radius,
+            interior: brush_around(size, radius, radius, radius),
+            edges: vec![vec![]; size * size],
+        };
+        for x in 0..size {
+            for y in 0..size {
+                if brushes.is_edge(x, y) {
+                    brushes.edges[x * size + y] = brush_around(size, radius, x, y);
                 }
             }
         }
+        brushes
+    }
+
+    fn is_edge(&self, x: usize, y: usize) -> bool {
+        x < self.radius
+            || y < self.radius
+            || x + self.radius >= self.size
+            || y + self.radius >= self.size
+    }
+
+    fn get(&self, x: usize, y: usize) -> &[Brush] {
+        if self.is_edge(x, y) {
+            &self.edges[x * self.size + y]
+        } else {
+            &self.interior
+        }
     }
-    erosion_brushs
 }
 
+/// Weights falling off with the distance from the sample, for the part of the brush inside the map
+fn brush_around(size: usize, radius: usize, x: usize, y: usize) -> Vec<Brush> {
+    let radius = radius as isize;
+    let mut brush = vec![];
+    for by in -radius..=radius {
+        for bx in -radius..=radius {
+            let sqr_dst = (bx * bx + by * by) as f32;
+            let (coord_x, coord_y) = (x as isize + bx, y as isize + by);
+            if sqr_dst < (radius * radius) as f32
+                && coord_x >= 0
+                && coord_y >= 0
+                && coord_x < size as isize
+                && coord_y < size as isize
+            {
+                brush.push(Brush {
+                    x: bx,
+                    y: by,
+                    weight: 1.0 - sqr_dst.sqrt() / radius as f32,
+                });
+            }
+        }
+    }
 
+    let weight_sum: f32 = brush.iter().map(|x| x.weight).sum();
+    for b in brush.iter_mut() {
+        b.weight /= weight_sum;
+    }
+    brush
+}
 
 struct HeightAndGradient {
     height: f32,
@@ -271,18 +601,14 @@ struct HeightAndGradient {
     gradient_y: f32,
 }
 
+/// Height and gradient at an offset inside the cell starting at `coord_x`, `coord_y`
 fn calculate_height_and_gradient(
     map: &Vec<Vec<f32>>,
-    pos_x: f32,
-    pos_y: f32,
+    coord_x: usize,
+    coord_y: usize,
+    x: f32,
+    y: f32,
 ) -> HeightAndGradient {
-    
-    let coord_x = pos_x as usize;
-    let coord_y = pos_y as usize;
-
-    // Calculate droplet's offset inside the cell
-    let x = pos_x - coord_x as f32;
-    let y = pos_y - coord_y as f32;
 
     // Ensure you don't exceed bounds
     if coord_x + 1 >= map.len() || coord_y + 1 >= map[coord_x].len() {
@@ -310,3 +636,111 @@ fn calculate_height_and_gradient(
         gradient_y,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brush_weights_add_up_to_one() {
+        let size = 20;
+        for radius in [2, 3, 8] {
+            let brushes = ErosionBrushes::new(size, radius);
+            let corners = [(0, 0), (0, size - 1), (size - 1, 0), (size - 1, size - 1)];
+            let edges = [(0, 7), (size - 1, 12), (9, 1), (radius - 1, radius)];
+            let interior = [
+                (radius, radius),
+                (size / 2, size / 2),
+                (size - radius - 1, 11),
+            ];
+            for (x, y) in corners.into_iter().chain(edges) {
+                assert!(brushes.is_edge(x, y));
+            }
+            for (x, y) in interior {
+                assert!(!brushes.is_edge(x, y));
+            }
+
+            for (x, y) in corners.into_iter().chain(edges).chain(interior) {
+                let brush = brushes.get(x, y);
+                let sum: f32 = brush.iter().map(|b| b.weight).sum();
+                assert!(
+                    (sum - 1.0).abs() < 1e-5,
+                    "radius {radius} at ({x}, {y}) sums to {sum}"
+                );
+                for b in brush {
+                    let (bx, by) = (x as isize + b.x, y as isize + b.y);
+                    assert!(bx >= 0 && by >= 0 && bx < size as isize && by < size as isize);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn brushes_are_shared() {
+        let brushes = ErosionBrushes::cached(57, 5);
+        assert!(Arc::ptr_eq(&brushes, &ErosionBrushes::cached(57, 5)));
+        assert!(!Arc::ptr_eq(&brushes, &ErosionBrushes::cached(57, 4)));
+    }
+
+    #[test]
+    fn droplet_lowers_a_slope() {
+        let size = 32;
+        let map: NoiseMap = (0..size)
+            .map(|x| vec![0.1 + x as f32 * 0.02; size])
+            .collect();
+        let mut delta = vec![vec![0.0; size]; size];
+        let erosion = HydraulicErosion::default();
+
+        erosion.simulate_droplet(
+            &map,
+            DropletChanges {
+                delta: &mut delta,
+                maps: None,
+                path: None,
+            },
+            &ErosionBrushes::new(size, erosion.erosion_radius),
+            (16, 16),
+            Vec2::splat(0.5),
+        );
+        assert!(delta[16][16] < 0.0, "{}", delta[16][16]);
+    }
+
+    #[test]
+    fn clamp_keeps_samples_within_their_neighbours() {
+        let mut map = vec![vec![0.5; 3]; 3];
+        map[1][1] = 0.4;
+        map[0][0] = 0.3;
+        let mut delta = vec![vec![0.0; 3]; 3];
+        delta[1][1] = 1.0;
+        delta[2][2] = -0.1;
+
+        let next = clamp_to_neighbours(&map, &delta);
+        assert_eq!(next[1][1], 0.5);
+        assert_eq!(next[2][2], 0.4);
+        assert_eq!(next[0][0], 0.3);
+    }
+
+    #[test]
+    fn clamped_erosion_stays_within_the_heights() {
+        let generator = crate::generator::TerrainGenerator {
+            chunk_size: 16,
+            ..default()
+        };
+        let erosion = HydraulicErosion {
+            iterations: 4000,
+            clamp_to_neighbours: true,
+            ..default()
+        };
+        let padding = erosion.padding();
+        let mut map = generator.generate_padded_noise_map(IVec2::ZERO, padding);
+        let (low, high) = map
+            .iter()
+            .flatten()
+            .fold((f32::MAX, f32::MIN), |(low, high), h| (low.min(*h), high.max(*h)));
+
+        let original = map.clone();
+        erosion.erode(&mut map, &generator.pass_context(IVec2::ZERO, padding));
+        assert_ne!(map, original);
+        assert!(map.iter().flatten().all(|h| (low..=high).contains(h)));
+    }
+}
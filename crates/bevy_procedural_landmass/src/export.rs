@@ -0,0 +1,314 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{generator::TerrainGenerator, NoiseMap};
+
+/// File formats heightmaps can be exported as
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TerrainExportFormat {
+    /// 16 bit grayscale png
+    Png16,
+    /// Raw little endian 16 bit heights
+    R16,
+    /// Raw little endian 32 bit float heights
+    R32,
+}
+
+impl TerrainExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TerrainExportFormat::Png16 => "png",
+            TerrainExportFormat::R16 => "r16",
+            TerrainExportFormat::R32 => "r32",
+        }
+    }
+}
+
+/// Contents of the `.json` sidecar written next to exported heightmaps, see [`TerrainGenerator::export_chunks`]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TerrainExportSidecar {
+    /// Extension of the heightmap file
+    pub format: String,
+    pub width: usize,
+    pub height: usize,
+    pub chunk_size: usize,
+    pub chunk_min: [i32; 2],
+    pub chunk_max: [i32; 2],
+    /// World position of the first sample
+    pub origin: [f32; 2],
+    /// World units between samples
+    pub texel_size: f32,
+    pub world_scale: f32,
+    pub height_multiplier: f32,
+    /// World height of a sample at 1.0
+    pub max_height: f32,
+}
+
+impl TerrainExportSidecar {
+    /// Where the sidecar of a heightmap goes, the full file name with `.json` added, so
+    /// exports that only differ by format don't overwrite each other's sidecar
+    pub fn path(heightmap: impl AsRef<Path>) -> PathBuf {
+        let mut path = heightmap.as_ref().as_os_str().to_owned();
+        path.push(".json");
+        path.into()
+    }
+}
+
+#[derive(Debug)]
+pub enum TerrainExportError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for TerrainExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainExportError::Io(e) => write!(f, "failed to write heightmap: {e}"),
+            TerrainExportError::Image(e) => write!(f, "failed to encode heightmap: {e}"),
+            TerrainExportError::Json(e) => write!(f, "failed to write heightmap sidecar: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TerrainExportError {}
+
+impl From<std::io::Error> for TerrainExportError {
+    fn from(e: std::io::Error) -> Self {
+        TerrainExportError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for TerrainExportError {
+    fn from(e: image::ImageError) -> Self {
+        TerrainExportError::Image(e)
+    }
+}
+
+impl From<serde_json::Error> for TerrainExportError {
+    fn from(e: serde_json::Error) -> Self {
+        TerrainExportError::Json(e)
+    }
+}
+
+impl TerrainGenerator {
+    /// Heights of a chunk after the passes, the same heights used for the chunk mesh
+    ///
+    /// Doesn't need a running app, but when using [`TerrainNormalization::Estimated`](crate::noise::TerrainNormalization::Estimated)
+    /// call [`TerrainGenerator::update_normalization`] first.
+    pub fn generate_height_map(&self, position: IVec2) -> NoiseMap {
+        self.generate_passes(position).0
+    }
+
+    /// Heights of every chunk from `min` to `max` (inclusive) stitched into one map,
+    /// neighbouring chunks share their edge so the result is `chunks * chunk_size + 1` wide
+    pub fn generate_stitched_height_map(&self, min: IVec2, max: IVec2) -> NoiseMap {
+        let (min, max) = (min.min(max), min.max(max));
+        let chunks = (max - min + IVec2::ONE).as_uvec2();
+        let width = chunks.x as usize * self.chunk_size + 1;
+        let height = chunks.y as usize * self.chunk_size + 1;
+
+        let mut map = vec![vec![0f32; height]; width];
+        for chunk_y in min.y..=max.y {
+            for chunk_x in min.x..=max.x {
+                let chunk = self.generate_height_map(IVec2::new(chunk_x, chunk_y));
+                let start_x = (chunk_x - min.x) as usize * self.chunk_size;
+                let start_y = (chunk_y - min.y) as usize * self.chunk_size;
+                for (x, column) in chunk.iter().enumerate() {
+                    map[start_x + x][start_y..start_y + column.len()].copy_from_slice(column);
+                }
+            }
+        }
+        map
+    }
+
+    /// Exports a chunk's heights, see [`TerrainGenerator::export_chunks`]
+    pub fn export_chunk(
+        &self,
+        position: IVec2,
+        path: impl AsRef<Path>,
+        format: TerrainExportFormat,
+    ) -> Result<(), TerrainExportError> {
+        self.export_chunks(position, position, path, format)
+    }
+
+    /// Exports the heights of the chunks from `min` to `max` (inclusive) stitched into one file,
+    /// along with a [`TerrainExportSidecar`] describing the world scale and height multiplier
+    pub fn export_chunks(
+        &self,
+        min: IVec2,
+        max: IVec2,
+        path: impl AsRef<Path>,
+        format: TerrainExportFormat,
+    ) -> Result<(), TerrainExportError> {
+        let path = path.as_ref();
+        let (min, max) = (min.min(max), min.max(max));
+        let map = self.generate_stitched_height_map(min, max);
+        write_height_map(&map, path, format)?;
+
+        // world position of the first texel, chunks are centered on their position
+        let origin = (min.as_vec2() - Vec2::splat(0.5)) * self.world_scale;
+        let sidecar = TerrainExportSidecar {
+            format: format.extension().to_string(),
+            width: map.len(),
+            height: map.first().map_or(0, |x| x.len()),
+            chunk_size: self.chunk_size,
+            chunk_min: min.to_array(),
+            chunk_max: max.to_array(),
+            origin: origin.to_array(),
+            texel_size: self.world_scale / self.chunk_size as f32,
+            world_scale: self.world_scale,
+            height_multiplier: self.height_multiplier,
+            max_height: self.height_multiplier * self.world_scale,
+        };
+        // non-finite numbers are written as null, so the sidecar is always valid json
+        let mut json = serde_json::to_string_pretty(&sidecar)?;
+        json.push('\n');
+        fs::write(TerrainExportSidecar::path(path), json)?;
+        Ok(())
+    }
+}
+
+/// Writes heights row by row, 16 bit formats clamp the heights to 0.0 - 1.0
+pub fn write_height_map(
+    map: &NoiseMap,
+    path: impl AsRef<Path>,
+    format: TerrainExportFormat,
+) -> Result<(), TerrainExportError> {
+    let width = map.len();
+    let height = map.first().map_or(0, |x| x.len());
+    let to_u16 = |h: f32| (h.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+    let rows = (0..height).flat_map(|y| (0..width).map(move |x| map[x][y]));
+
+    match format {
+        TerrainExportFormat::Png16 => {
+            let data: Vec<u16> = rows.map(to_u16).collect();
+            let image = image::ImageBuffer::<image::Luma<u16>, _>::from_raw(
+                width as u32,
+                height as u32,
+                data,
+            )
+            .expect("buffer matches the image size");
+            image.save_with_format(path, image::ImageFormat::Png)?;
+        }
+        TerrainExportFormat::R16 => {
+            let data: Vec<u8> = rows.flat_map(|h| to_u16(h).to_le_bytes()).collect();
+            fs::write(path, data)?;
+        }
+        TerrainExportFormat::R32 => {
+            let data: Vec<u8> = rows.flat_map(|h| h.to_le_bytes()).collect();
+            fs::write(path, data)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pass::TerrainPasses;
+
+    /// Heights read back from an export, in the same layout as the map
+    fn read_height_map(path: &Path, format: TerrainExportFormat, width: usize) -> NoiseMap {
+        let heights: Vec<f32> = match format {
+            TerrainExportFormat::Png16 => image::open(path)
+                .unwrap()
+                .into_luma16()
+                .into_raw()
+                .into_iter()
+                .map(|h| h as f32 / u16::MAX as f32)
+                .collect(),
+            TerrainExportFormat::R16 => fs::read(path)
+                .unwrap()
+                .chunks_exact(2)
+                .map(|h| u16::from_le_bytes([h[0], h[1]]) as f32 / u16::MAX as f32)
+                .collect(),
+            TerrainExportFormat::R32 => fs::read(path)
+                .unwrap()
+                .chunks_exact(4)
+                .map(|h| f32::from_le_bytes([h[0], h[1], h[2], h[3]]))
+                .collect(),
+        };
+        let height = heights.len() / width;
+        (0..width)
+            .map(|x| (0..height).map(|y| heights[y * width + x]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn exports_read_back() {
+        let generator = TerrainGenerator {
+            chunk_size: 8,
+            passes: TerrainPasses(vec![]),
+            ..default()
+        };
+        let (min, max) = (IVec2::new(-1, 0), IVec2::new(0, 1));
+        let map = generator.generate_stitched_height_map(min, max);
+        let dir = std::env::temp_dir().join(format!("landmass_export_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (format, tolerance) in [
+            (TerrainExportFormat::Png16, 1.0 / u16::MAX as f32),
+            (TerrainExportFormat::R16, 1.0 / u16::MAX as f32),
+            (TerrainExportFormat::R32, 0.0),
+        ] {
+            // the same name for every format, only the extension differs
+            let path = dir.join("chunks").with_extension(format.extension());
+            generator.export_chunks(max, min, &path, format).unwrap();
+
+            let read = read_height_map(&path, format, map.len());
+            assert_eq!(read.len(), 17);
+            assert_eq!(read[0].len(), 17);
+            for (read, height) in read.iter().flatten().zip(map.iter().flatten()) {
+                assert!((read - height.clamp(0.0, 1.0)).abs() <= tolerance, "{format:?}");
+            }
+
+            let sidecar = fs::read_to_string(dir.join(format!("chunks.{}.json", format.extension())));
+            let sidecar: TerrainExportSidecar = serde_json::from_str(&sidecar.unwrap()).unwrap();
+            assert_eq!(
+                sidecar,
+                TerrainExportSidecar {
+                    format: format.extension().to_string(),
+                    width: 17,
+                    height: 17,
+                    chunk_size: 8,
+                    chunk_min: [-1, 0],
+                    chunk_max: [0, 1],
+                    origin: [-1.5 * generator.world_scale, -0.5 * generator.world_scale],
+                    texel_size: generator.world_scale / 8.0,
+                    world_scale: generator.world_scale,
+                    height_multiplier: generator.height_multiplier,
+                    max_height: generator.height_multiplier * generator.world_scale,
+                }
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sidecar_is_valid_json_with_non_finite_numbers() {
+        let generator = TerrainGenerator {
+            chunk_size: 4,
+            height_multiplier: f32::NAN,
+            passes: TerrainPasses(vec![]),
+            ..default()
+        };
+        let dir = std::env::temp_dir().join(format!("landmass_nan_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chunk.r32");
+        generator
+            .export_chunk(IVec2::ZERO, &path, TerrainExportFormat::R32)
+            .unwrap();
+
+        let sidecar = fs::read_to_string(TerrainExportSidecar::path(&path)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+        assert!(json["height_multiplier"].is_null());
+        assert_eq!(json["chunk_size"], 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::prelude::*;
+
+use crate::noise::{TerrainCurve, TerrainCurveMode};
+
+/// Lowers the terrain away from a center point, used to make islands and continents
+#[derive(Clone, Default, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum TerrainFalloff {
+    #[default]
+    None,
+    Mask(FalloffMask),
+}
+
+impl TerrainFalloff {
+    pub fn apply(&self, height: f32, world_pos: Vec2) -> f32 {
+        match self {
+            TerrainFalloff::None => height,
+            TerrainFalloff::Mask(x) => x.apply(height, world_pos),
+        }
+    }
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct FalloffMask {
+    pub shape: FalloffShape,
+    pub blend: FalloffBlend,
+    /// Center of the mask in world units
+    pub center: Vec2,
+    /// Distance from the center in world units where the terrain is fully lowered
+    #[inspector(min = 1.0, max = 100_000.0)]
+    pub radius: f32,
+    /// Falloff from the center (0.0) to the radius (1.0)
+    pub curve: TerrainCurve,
+}
+
+impl Default for FalloffMask {
+    fn default() -> Self {
+        Self {
+            shape: FalloffShape::Radial,
+            blend: FalloffBlend::Multiply,
+            center: Vec2::ZERO,
+            radius: 1000.0,
+            curve: TerrainCurve {
+                mode: TerrainCurveMode::CubicIn,
+                ..default()
+            },
+        }
+    }
+}
+
+impl FalloffMask {
+    /// Mask value at a world-space position, 1.0 keeps the terrain and 0.0 is fully lowered
+    pub fn get(&self, world_pos: Vec2) -> f32 {
+        let delta = world_pos - self.center;
+        let distance = match self.shape {
+            FalloffShape::Radial => delta.length(),
+            FalloffShape::Square => delta.x.abs().max(delta.y.abs()),
+        };
+        let t = (distance / self.radius).clamp(0.0, 1.0);
+        1.0 - self.curve.get(t)
+    }
+
+    pub fn apply(&self, height: f32, world_pos: Vec2) -> f32 {
+        let mask = self.get(world_pos);
+        match self.blend {
+            FalloffBlend::Multiply => height * mask,
+            FalloffBlend::Subtract => (height - (1.0 - mask)).max(0.0),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Reflect)]
+pub enum FalloffShape {
+    Radial,
+    Square,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Reflect)]
+pub enum FalloffBlend {
+    Multiply,
+    Subtract,
+}
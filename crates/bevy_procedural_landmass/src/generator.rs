@@ -4,7 +4,7 @@ use bevy::{
 };
 use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
 
-use crate::{noise::*, erosion::TerrainErosion, regions::TerrainRegions, NoiseMap};
+use crate::{noise::*, biome::{BiomeMap, TerrainBiomes}, erosion::TerrainErosion, pass::*, seed::TerrainSeed, regions::TerrainRegions, util, NoiseMap};
 
 #[derive(Clone, Resource, Reflect, InspectorOptions)]
 #[reflect(Resource, InspectorOptions)]
@@ -22,8 +22,11 @@ pub struct TerrainGenerator {
     pub mesh_mode: TerrainMeshMode,
     pub sampler: TerrainSampler,
     pub noise: TerrainNoise,
-    pub erosion: TerrainErosion,
+    /// Run in order on the noise, before the mesh and textures are made
+    pub passes: TerrainPasses,
     pub regions: TerrainRegions,
+    /// Replaces `regions` with per biome colors when enabled
+    pub biomes: TerrainBiomes,
 }
 
 impl Default for TerrainGenerator {
@@ -37,58 +40,144 @@ impl Default for TerrainGenerator {
             noise: TerrainNoise::default(),
             world_scale: 500.0,
             regions: TerrainRegions::default(),
-            erosion: TerrainErosion::default(),
+            passes: TerrainPasses::default(),
+            biomes: TerrainBiomes::default(),
         }
     }
 }
 
 impl TerrainGenerator {
+    /// World-space position (relative to the world origin) of a noise map sample in a chunk.
+    ///
+    /// Neighbouring chunks share their border samples, so the last row of one chunk lands
+    /// on exactly the same world position as the first row of the next.
+    pub fn world_position(&self, position: IVec2, x: usize, y: usize) -> Vec2 {
+        self.texel_world_position(position * self.chunk_size as i32 + IVec2::new(x as i32, y as i32))
+    }
+
+    /// World-space position of a sample counted from the world origin, the same sample gets the
+    /// same position in every chunk
+    pub fn texel_world_position(&self, texel: IVec2) -> Vec2 {
+        util::texel_world_position(texel, self.chunk_size, self.world_scale)
+    }
+
+    /// Seeds every layer of the terrain from one world seed, each layer gets its own sub-seed
+    pub fn set_seed(&mut self, seed: impl Into<TerrainSeed>) {
+        let seed = seed.into();
+        self.noise.seed = seed;
+        if let TerrainBiomes::Climate(x) = &mut self.biomes {
+            x.set_seed(seed);
+        }
+        for pass in self.passes.0.iter_mut() {
+            if let TerrainPassMode::Erosion(TerrainErosion::Hydraulic(x)) = &mut pass.mode {
+                x.seed = seed.derive("erosion");
+            }
+        }
+    }
+
+    /// Estimates the range of every noise using [`TerrainNormalization::Estimated`]
+    pub fn update_normalization(&mut self) {
+        self.noise.update_normalization();
+        self.biomes.update_normalization();
+    }
+
     pub fn generate_noise_map(&self, position: IVec2) -> NoiseMap {
+        self.generate_padded_noise_map(position, 0)
+    }
 
-        let size = self.chunk_size + 1;
-        
-        let mut noise_map = vec![vec![0f32; size]; size];
+    /// Noise map with `padding` extra samples on every side, taken from the neighbouring chunks
+    pub fn generate_padded_noise_map(&self, position: IVec2, padding: usize) -> NoiseMap {
+        // one extra sample so the edges line up with the neighbouring chunks
+        let size = self.chunk_size + 1 + padding * 2;
 
-        let half_size = size as f32 / 2.0;
+        let mut noise_map = vec![vec![0f32; size]; size];
 
         for y in 0..size {
             for x in 0..size {
-                let pos = Vec2::new(
-                    (x as f32 - half_size)
-                        + (position.x as f32 * size as f32)
-                        + self.noise.offset.x,
-                    (y as f32 - half_size)
-                        + (position.y as f32 * size as f32)
-                        + self.noise.offset.y,
-                ) / (self.noise.scale * size as f32);
-
-                noise_map[x][y] = self.noise.get(pos, self.noise.seed);
+                let pos = self.padded_world_position(position, padding, x, y);
+                noise_map[x][y] = self.noise.get(pos);
             }
         }
         noise_map
     }
 
-    pub fn generate_erosion(&self, map: &mut NoiseMap) -> Option<Vec<Vec<Vec3>>> {
-        match &self.erosion {
-            TerrainErosion::None => None,
-            TerrainErosion::Hydraulic(x) => Some(x.erode(
-                map,
-                self.chunk_size,
-                #[cfg(debug_rain)]
-                self.world_scale,
-                #[cfg(debug_rain)]
-                self.height_multiplier,
-            )),
+    fn padded_world_position(&self, position: IVec2, padding: usize, x: usize, y: usize) -> Vec2 {
+        let texel = position * self.chunk_size as i32 + IVec2::new(x as i32, y as i32);
+        self.texel_world_position(texel - IVec2::splat(padding as i32))
+    }
+
+    /// Context given to the passes of a chunk
+    pub fn pass_context(&self, position: IVec2, padding: usize) -> TerrainPassContext<'_> {
+        TerrainPassContext {
+            chunk: position,
+            chunk_size: self.chunk_size,
+            world_scale: self.world_scale,
+            height_multiplier: self.height_multiplier,
+            seed: self.noise.seed,
+            padding,
+            origin: self.padded_world_position(position, padding, 0, 0),
+            origin_texel: position * self.chunk_size as i32 - IVec2::splat(padding as i32),
+            texel_size: self.world_scale / self.chunk_size as f32,
+            noise: &self.noise,
+            earlier_passes: &[],
         }
     }
 
-    pub fn generate_color_map_image(&self, noise_map: &NoiseMap) -> Vec<u8> {
-        let mut image_data = vec![0u8; (self.chunk_size * self.chunk_size) as usize * 4];
-        for y in 0..self.chunk_size {
-            for x in 0..self.chunk_size {
-                let height = noise_map[x as usize][y as usize];
-                let color = self.regions.get_color(height);
-                let j = ((y * self.chunk_size) + x) as usize * 4;
+    /// Heights of a chunk with every enabled pass applied, along with the other results of the passes
+    pub fn generate_passes(&self, position: IVec2) -> (NoiseMap, TerrainPassOutput) {
+        let padding = self.passes.padding();
+        let mut map = self.generate_padded_noise_map(position, padding);
+        let mut output = self.passes.apply(&mut map, &self.pass_context(position, padding));
+
+        // crop back to the chunk
+        let size = self.chunk_size + 1;
+        let crop = |map: NoiseMap| -> NoiseMap {
+            map.into_iter()
+                .skip(padding)
+                .take(size)
+                .map(|column| column[padding..padding + size].to_vec())
+                .collect()
+        };
+        output.water_map = output.water_map.map(&crop);
+        output.erosion_maps = output.erosion_maps.map(|x| x.map(&crop));
+        output.lakes = output.lakes.map(|x| x.crop(padding, size));
+        (crop(map), output)
+    }
+
+    /// Biome of every sample in a chunk, `None` when biomes are disabled
+    pub fn generate_biome_map(&self, position: IVec2, noise_map: &NoiseMap) -> Option<BiomeMap> {
+        let TerrainBiomes::Climate(biomes) = &self.biomes else {
+            return None;
+        };
+        let size = noise_map.len();
+        let mut biome_map = vec![vec![0u8; size]; size];
+        for y in 0..size {
+            for x in 0..size {
+                let pos = self.world_position(position, x, y);
+                biome_map[x][y] = biomes.get(pos, noise_map[x][y]);
+            }
+        }
+        Some(biome_map)
+    }
+
+    /// Colors the heights by region, or by the regions of each biome when given a biome map
+    pub fn generate_color_map_image(
+        &self,
+        noise_map: &NoiseMap,
+        biome_map: Option<&BiomeMap>,
+    ) -> Vec<u8> {
+        let size = noise_map.len();
+        let mut image_data = vec![0u8; size * size * 4];
+        for y in 0..size {
+            for x in 0..size {
+                let height = noise_map[x][y];
+                let color = match (&self.biomes, biome_map) {
+                    (TerrainBiomes::Climate(biomes), Some(map)) => {
+                        biomes.get_color(map[x][y], height)
+                    }
+                    _ => self.regions.get_color(height),
+                };
+                let j = ((y * size) + x) * 4;
                 image_data[j] = (color.r() * 255.0) as u8;
                 image_data[j + 1] = (color.g() * 255.0) as u8;
                 image_data[j + 2] = (color.b() * 255.0) as u8;
@@ -99,11 +188,12 @@ impl TerrainGenerator {
     }
 
     pub fn generate_height_map_image(&self, noise_map: &NoiseMap) -> Vec<u8> {
-        let mut image_data = vec![0u8; (self.chunk_size * self.chunk_size) as usize * 4];
-        for y in 0..self.chunk_size {
-            for x in 0..self.chunk_size {
-                let height = noise_map[x as usize][y as usize];
-                let j = ((y * self.chunk_size) + x) as usize * 4;
+        let size = noise_map.len();
+        let mut image_data = vec![0u8; size * size * 4];
+        for y in 0..size {
+            for x in 0..size {
+                let height = noise_map[x][y];
+                let j = ((y * size) + x) * 4;
                 let val = (height * 255.0) as u8;
                 image_data[j] = val;
                 image_data[j + 1] = val;
@@ -115,7 +205,9 @@ impl TerrainGenerator {
     }
 
     pub fn generate_mesh(&self, noise_map: &NoiseMap) -> Mesh {
-        let size = self.chunk_size;
+        // vertices per side, the noise map includes the shared edge
+        let size = noise_map.len();
+        let cell_size = self.world_scale / (size - 1) as f32;
 
         let num_vertices = size * size;
         let num_indices = (size - 1) * (size - 1) * 6;
@@ -125,19 +217,19 @@ impl TerrainGenerator {
         let mut uvs: Vec<[f32; 2]> = Vec::with_capacity(num_vertices);
         let mut indices: Vec<u32> = Vec::with_capacity(num_indices);
 
-        let half_size = size as f32 / 2.0;
+        let half_world_size = self.world_scale / 2.0;
         for y in 0..size {
             for x in 0..size {
                 let i = (y * size) + x;
                 // find the position of the vertex and center, with height_multiplier
                 let pos = [
-                    (x as f32 - half_size) * self.world_scale / size as f32,
+                    x as f32 * cell_size - half_world_size,
                     (noise_map[x][y] * self.height_multiplier * self.world_scale) as f32,
-                    (y as f32 - half_size) * self.world_scale / size as f32,
+                    y as f32 * cell_size - half_world_size,
                 ];
 
                 positions.push(pos);
-                uvs.push([x as f32 / size as f32, y as f32 / size as f32]);
+                uvs.push([x as f32 / (size - 1) as f32, y as f32 / (size - 1) as f32]);
 
                 if x < size - 1 && y < size - 1 {
                     let a = i;
@@ -211,3 +303,165 @@ pub enum TerrainSampler {
     Linear,
     Nearest,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        falloff::TerrainFalloff, river::RiverNetwork, shallow_water::ShallowWaterErosion,
+        thermal::ThermalErosion,
+    };
+
+    fn generator(chunk_size: usize) -> TerrainGenerator {
+        TerrainGenerator {
+            chunk_size,
+            ..default()
+        }
+    }
+
+    /// The last row and column of chunk (0, 0) have to be the first of chunks (1, 0) and (0, 1)
+    fn assert_edges_match(chunk_size: usize, generate: impl Fn(IVec2) -> NoiseMap) {
+        let chunk = generate(IVec2::ZERO);
+        let right = generate(IVec2::new(1, 0));
+        let up = generate(IVec2::new(0, 1));
+        for i in 0..=chunk_size {
+            assert_eq!(
+                chunk[chunk_size][i], right[0][i],
+                "x edge of chunk size {chunk_size} differs at {i}"
+            );
+            assert_eq!(
+                chunk[i][chunk_size], up[i][0],
+                "y edge of chunk size {chunk_size} differs at {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn noise_map_edges_match() {
+        for chunk_size in [16, 33] {
+            let generator = generator(chunk_size);
+            assert_edges_match(chunk_size, |position| generator.generate_noise_map(position));
+        }
+    }
+
+    #[test]
+    fn pass_edges_match() {
+        for chunk_size in [16, 33] {
+            let generator = generator(chunk_size);
+            assert_edges_match(chunk_size, |position| generator.generate_passes(position).0);
+        }
+    }
+
+    #[test]
+    fn thermal_pass_edges_match() {
+        for chunk_size in [16, 33] {
+            let mut generator = generator(chunk_size);
+            // a low talus angle so material moves across the chunk edges
+            generator.passes = TerrainPasses(vec![TerrainPass::new(TerrainPassMode::Erosion(
+                TerrainErosion::Thermal(ThermalErosion {
+                    talus_angle: 2.0,
+                    iterations: 8,
+                    ..default()
+                }),
+            ))]);
+            assert_ne!(
+                generator.generate_passes(IVec2::ZERO).0,
+                generator.generate_noise_map(IVec2::ZERO)
+            );
+            assert_edges_match(chunk_size, |position| generator.generate_passes(position).0);
+        }
+    }
+
+    #[test]
+    fn hydraulic_erosion_is_the_same_in_any_order() {
+        let chunk_size = 32;
+        let generator = generator(chunk_size);
+        assert!(generator.passes.0.iter().any(|pass| pass.enabled
+            && matches!(
+                pass.mode,
+                TerrainPassMode::Erosion(TerrainErosion::Hydraulic(_))
+            )));
+
+        let (a, b) = (IVec2::ZERO, IVec2::new(1, 0));
+        let first = [a, b, a].map(|position| generator.generate_passes(position).0);
+        // a clone on another thread, like the chunk tasks get
+        let clone = generator.clone();
+        let second =
+            std::thread::spawn(move || [b, a].map(|position| clone.generate_passes(position).0))
+                .join()
+                .unwrap();
+
+        assert_ne!(first[0], generator.generate_noise_map(a));
+        assert_eq!(first[0], first[2]);
+        assert_eq!(first[0], second[1]);
+        assert_eq!(first[1], second[0]);
+        for i in 0..=chunk_size {
+            assert_eq!(
+                first[0][chunk_size][i], second[0][0][i],
+                "shared edge differs at {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn height_at_follows_the_earlier_passes() {
+        let mut generator = generator(16);
+        generator.passes = TerrainPasses(vec![
+            TerrainPass::new(TerrainPassMode::Falloff(TerrainFalloff::Mask(default()))),
+            TerrainPass::new(TerrainPassMode::Clamp { min: 0.2, max: 0.6 }),
+        ]);
+        let position = IVec2::new(1, -1);
+        let (map, _) = generator.generate_passes(position);
+        assert_ne!(map, generator.generate_noise_map(position));
+
+        let context = TerrainPassContext {
+            earlier_passes: &generator.passes.0,
+            ..generator.pass_context(position, 0)
+        };
+        for (x, column) in map.iter().enumerate() {
+            for (y, height) in column.iter().enumerate() {
+                assert_eq!(*height, context.height_at(context.world_position(x, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn shallow_water_pass_edges_match() {
+        for chunk_size in [16, 33] {
+            let mut generator = generator(chunk_size);
+            generator.passes = TerrainPasses(vec![TerrainPass::new(TerrainPassMode::Erosion(
+                TerrainErosion::ShallowWater(ShallowWaterErosion {
+                    iterations: 6,
+                    ..default()
+                }),
+            ))]);
+            assert_ne!(
+                generator.generate_passes(IVec2::ZERO).0,
+                generator.generate_noise_map(IVec2::ZERO)
+            );
+            assert_edges_match(chunk_size, |position| generator.generate_passes(position).0);
+            assert_edges_match(chunk_size, |position| {
+                generator.generate_passes(position).1.water_map.unwrap()
+            });
+        }
+    }
+
+    #[test]
+    fn river_pass_edges_match() {
+        for chunk_size in [16, 33] {
+            let mut generator = generator(chunk_size);
+            // plenty of sources so rivers cross the chunk edges
+            generator.passes = TerrainPasses(vec![TerrainPass::new(TerrainPassMode::Rivers(
+                RiverNetwork {
+                    source_height: 0.0,
+                    source_chance: 0.5,
+                    ..default()
+                },
+            ))]);
+            let (map, output) = generator.generate_passes(IVec2::ZERO);
+            assert!(!output.rivers.unwrap().is_empty());
+            assert_ne!(map, generator.generate_noise_map(IVec2::ZERO));
+            assert_edges_match(chunk_size, |position| generator.generate_passes(position).0);
+        }
+    }
+}
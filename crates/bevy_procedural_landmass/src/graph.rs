@@ -0,0 +1,367 @@
+use std::cell::RefCell;
+
+use bevy::prelude::*;
+use bevy_inspector_egui::prelude::*;
+
+use noisy_bevy::simplex_noise_2d_seeded;
+
+use crate::{noise::*, seed::TerrainSeed, source::TerrainCustomSource, util};
+
+/// Node based noise, lets several noise sources be combined into one height
+///
+/// Nodes are referenced by their index in `nodes`, and a node can only use nodes that come
+/// before it as inputs, anything else reads as 0.0. This keeps the graph free of cycles so it
+/// can be evaluated per sample from the chunk tasks.
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct TerrainNoiseGraph {
+    pub nodes: Vec<TerrainNoiseNode>,
+    /// Index of the node used as the output of the graph
+    pub output: usize,
+}
+
+impl Default for TerrainNoiseGraph {
+    /// Low plains with mountains selected by a mask
+    fn default() -> Self {
+        Self {
+            nodes: vec![
+                // 0: plains
+                TerrainNoiseNode::Source {
+                    mode: TerrainGraphSource::FMBSimplex(FMBSimplex::default()),
+                    frequency: 1.0,
+                    seed_offset: 0,
+                },
+                // 1: flatten the plains
+                TerrainNoiseNode::ScaleBias {
+                    input: 0,
+                    scale: 0.3,
+                    bias: 0.15,
+                },
+                // 2: mountains
+                TerrainNoiseNode::Source {
+                    mode: TerrainGraphSource::FMBSimplex(FMBSimplex::default()),
+                    frequency: 2.0,
+                    seed_offset: 1,
+                },
+                // 3: mask, simplex is in the -1..1 range
+                TerrainNoiseNode::Source {
+                    mode: TerrainGraphSource::Simplex,
+                    frequency: 0.25,
+                    seed_offset: 2,
+                },
+                // 4: remap mask to 0..1
+                TerrainNoiseNode::ScaleBias {
+                    input: 3,
+                    scale: 0.5,
+                    bias: 0.5,
+                },
+                // 5: mountains where the mask is high
+                TerrainNoiseNode::Select {
+                    a: 1,
+                    b: 2,
+                    mask: 4,
+                    threshold: 0.5,
+                    falloff: 0.1,
+                },
+            ],
+            output: 5,
+        }
+    }
+}
+
+/// Node values of the samples being worked out on a thread, kept so sampling doesn't allocate
+#[derive(Default)]
+struct GraphScratch {
+    values: Vec<f32>,
+    used: Vec<bool>,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<GraphScratch> = RefCell::new(GraphScratch::default());
+}
+
+impl TerrainNoiseGraph {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+            Ok(mut scratch) => self.eval(self.output, pos, seed, &mut scratch),
+            // a custom source sampling another graph
+            Err(_) => self.eval(self.output, pos, seed, &mut GraphScratch::default()),
+        })
+    }
+
+    /// Evaluates the nodes `index` depends on in order, so every node is sampled once per position,
+    /// only [`TerrainNoiseNode::Warp`] has to go back and sample its inputs somewhere else
+    fn eval(&self, index: usize, pos: Vec2, seed: TerrainSeed, scratch: &mut GraphScratch) -> f32 {
+        if index >= self.nodes.len() {
+            return 0.0;
+        }
+
+        // goes after the values of the positions still being worked out further up, beneath a warp
+        let base = scratch.values.len();
+        scratch.values.resize(base + index + 1, 0.0);
+        scratch.used.resize(base + index + 1, false);
+        self.mark_used(index, &mut scratch.used[base..]);
+
+        for current in 0..=index {
+            if scratch.used[base + current] {
+                let value = self.eval_node(current, pos, seed, base, scratch);
+                scratch.values[base + current] = value;
+            }
+        }
+
+        let value = scratch.values[base + index];
+        scratch.values.truncate(base);
+        scratch.used.truncate(base);
+        value
+    }
+
+    /// Value of a node from the values of the nodes before it, which start at `base` in the scratch
+    fn eval_node(
+        &self,
+        current: usize,
+        pos: Vec2,
+        seed: TerrainSeed,
+        base: usize,
+        scratch: &mut GraphScratch,
+    ) -> f32 {
+        // only allow inputs from earlier nodes, so we can't loop forever
+        let values = &scratch.values[base..base + current];
+        let input = |i: usize| values.get(i).copied().unwrap_or(0.0);
+
+        match &self.nodes[current] {
+            TerrainNoiseNode::Source {
+                mode,
+                frequency,
+                seed_offset,
+            } => mode.get(pos * *frequency, seed.layer(*seed_offset)),
+            TerrainNoiseNode::Constant(value) => *value,
+            TerrainNoiseNode::Add(a, b) => input(*a) + input(*b),
+            TerrainNoiseNode::Multiply(a, b) => input(*a) * input(*b),
+            TerrainNoiseNode::Min(a, b) => input(*a).min(input(*b)),
+            TerrainNoiseNode::Max(a, b) => input(*a).max(input(*b)),
+            TerrainNoiseNode::Lerp { a, b, mask } => {
+                util::lerp(input(*a), input(*b), input(*mask).clamp(0.0, 1.0))
+            }
+            TerrainNoiseNode::Select {
+                a,
+                b,
+                mask,
+                threshold,
+                falloff,
+            } => {
+                let mask = input(*mask);
+                let t = if *falloff > 0.0 {
+                    util::smoothstep(threshold - falloff, threshold + falloff, mask)
+                } else if mask < *threshold {
+                    0.0
+                } else {
+                    1.0
+                };
+                match t {
+                    t if t <= 0.0 => input(*a),
+                    t if t >= 1.0 => input(*b),
+                    t => util::lerp(input(*a), input(*b), t),
+                }
+            }
+            TerrainNoiseNode::ScaleBias {
+                input: i,
+                scale,
+                bias,
+            } => input(*i) * scale + bias,
+            TerrainNoiseNode::Curve { input: i, curve } => curve.get(input(*i)),
+            TerrainNoiseNode::Warp {
+                input: i,
+                warp,
+                strength,
+            } => {
+                let (i, warp) = (*i, *warp);
+                let x = input(warp);
+                let mut input_at = |i: usize, pos: Vec2| {
+                    if i < current {
+                        self.eval(i, pos, seed, scratch)
+                    } else {
+                        0.0
+                    }
+                };
+                // y samples the warp again further along, so x and y are not the same
+                let offset = Vec2::new(x, input_at(warp, pos + Vec2::new(5.2, 1.3)));
+                input_at(i, pos + (offset * 2.0 - Vec2::ONE) * *strength)
+            }
+        }
+    }
+
+    /// Marks which of the nodes up to `index` it reads from, directly or through other nodes
+    fn mark_used(&self, index: usize, used: &mut [bool]) {
+        used[index] = true;
+        for current in (0..=index).rev() {
+            if !used[current] {
+                continue;
+            }
+            for i in self.nodes[current].inputs() {
+                if i < current {
+                    used[i] = true;
+                }
+            }
+        }
+    }
+}
+
+/// A single node in a [`TerrainNoiseGraph`], inputs are indexes of other nodes
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub enum TerrainNoiseNode {
+    /// Samples noise, `frequency` scales the position and `seed_offset` picks a sub-seed
+    Source {
+        mode: TerrainGraphSource,
+        frequency: f32,
+        seed_offset: u64,
+    },
+    Constant(f32),
+    Add(usize, usize),
+    Multiply(usize, usize),
+    Min(usize, usize),
+    Max(usize, usize),
+    /// Blends from `a` to `b` by `mask`
+    Lerp { a: usize, b: usize, mask: usize },
+    /// Picks `a` below `threshold` and `b` above, blending over `falloff` on either side
+    Select {
+        a: usize,
+        b: usize,
+        mask: usize,
+        threshold: f32,
+        falloff: f32,
+    },
+    ScaleBias { input: usize, scale: f32, bias: f32 },
+    Curve { input: usize, curve: TerrainCurve },
+    /// Offsets the position `input` is sampled at by `warp`, remapped to -1..1 and scaled by `strength`
+    Warp {
+        input: usize,
+        warp: usize,
+        strength: f32,
+    },
+}
+
+impl TerrainNoiseNode {
+    /// Indexes of the nodes this one reads from
+    pub fn inputs(&self) -> impl Iterator<Item = usize> {
+        let inputs = match self {
+            TerrainNoiseNode::Source { .. } | TerrainNoiseNode::Constant(_) => [None; 3],
+            TerrainNoiseNode::Add(a, b)
+            | TerrainNoiseNode::Multiply(a, b)
+            | TerrainNoiseNode::Min(a, b)
+            | TerrainNoiseNode::Max(a, b) => [Some(*a), Some(*b), None],
+            TerrainNoiseNode::Lerp { a, b, mask } | TerrainNoiseNode::Select { a, b, mask, .. } => {
+                [Some(*a), Some(*b), Some(*mask)]
+            }
+            TerrainNoiseNode::ScaleBias { input, .. } | TerrainNoiseNode::Curve { input, .. } => {
+                [Some(*input), None, None]
+            }
+            TerrainNoiseNode::Warp { input, warp, .. } => [Some(*input), Some(*warp), None],
+        };
+        inputs.into_iter().flatten()
+    }
+}
+
+impl Default for TerrainNoiseNode {
+    fn default() -> Self {
+        TerrainNoiseNode::Constant(0.0)
+    }
+}
+
+/// Noise that can be sampled by a [`TerrainNoiseNode::Source`], same as [`TerrainNoiseMode`]
+/// without the graph itself, since reflection can't handle the graph containing itself
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum TerrainGraphSource {
+    FMBSimplex(FMBSimplex),
+    RidgedMultifractal(RidgedMultifractal),
+    Billow(Billow),
+    Octaves(OctaveNoise),
+    Cellular(Cellular),
+    Simplex,
+    Custom(TerrainCustomSource),
+}
+
+impl TerrainGraphSource {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        match self {
+            TerrainGraphSource::FMBSimplex(x) => x.get(pos, seed),
+            TerrainGraphSource::RidgedMultifractal(x) => x.get(pos, seed),
+            TerrainGraphSource::Billow(x) => x.get(pos, seed),
+            TerrainGraphSource::Octaves(x) => x.get(pos, seed),
+            TerrainGraphSource::Cellular(x) => x.get(pos, seed),
+            TerrainGraphSource::Simplex => simplex_noise_2d_seeded(pos, seed.noise_seed()),
+            TerrainGraphSource::Custom(x) => x.sample(pos, seed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(graph: &TerrainNoiseGraph, index: usize, pos: Vec2) -> f32 {
+        let mut scratch = GraphScratch::default();
+        let value = graph.eval(index, pos, TerrainSeed::default(), &mut scratch);
+        assert!(scratch.values.is_empty() && scratch.used.is_empty());
+        value
+    }
+
+    #[test]
+    fn inputs_are_evaluated_in_order() {
+        let graph = TerrainNoiseGraph {
+            nodes: vec![
+                TerrainNoiseNode::Constant(2.0),
+                TerrainNoiseNode::Constant(0.5),
+                TerrainNoiseNode::Multiply(0, 1),
+                // later nodes and itself read as 0.0
+                TerrainNoiseNode::Add(2, 4),
+                TerrainNoiseNode::Add(3, 3),
+                TerrainNoiseNode::Warp {
+                    input: 4,
+                    warp: 1,
+                    strength: 10.0,
+                },
+            ],
+            output: 5,
+        };
+        let seed = TerrainSeed::default();
+        assert_eq!(eval(&graph, 2, Vec2::ZERO), 1.0);
+        assert_eq!(eval(&graph, 3, Vec2::ZERO), 1.0);
+        assert_eq!(graph.get(Vec2::new(3.0, 7.0), seed), 2.0);
+        assert_eq!(eval(&graph, 6, Vec2::ZERO), 0.0);
+    }
+
+    #[test]
+    fn warp_matches_sampling_the_input_at_the_offset() {
+        let graph = TerrainNoiseGraph::default();
+        let warped = TerrainNoiseGraph {
+            nodes: [
+                graph.nodes.clone(),
+                vec![
+                    TerrainNoiseNode::Source {
+                        mode: TerrainGraphSource::Simplex,
+                        frequency: 0.5,
+                        seed_offset: 3,
+                    },
+                    TerrainNoiseNode::Warp {
+                        input: 5,
+                        warp: 6,
+                        strength: 0.3,
+                    },
+                ],
+            ]
+            .concat(),
+            output: 7,
+        };
+        let seed = TerrainSeed::default();
+        for pos in [Vec2::ZERO, Vec2::new(1.7, -4.2), Vec2::new(-12.5, 8.25)] {
+            let offset = Vec2::new(
+                eval(&warped, 6, pos),
+                eval(&warped, 6, pos + Vec2::new(5.2, 1.3)),
+            );
+            let expected = graph.get(pos + (offset * 2.0 - Vec2::ONE) * 0.3, seed);
+            assert_eq!(warped.get(pos, seed), expected);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+
+use crate::{seed::TerrainSeed, source::TerrainHeightSource, util};
+
+/// Height source backed by an image, so hand authored or externally generated heightmaps
+/// can be run through the rest of the terrain pipeline.
+///
+/// Use it with [`TerrainNoiseMode::Custom`](crate::noise::TerrainNoiseMode::Custom):
+/// ```ignore
+/// let heightmap = TerrainHeightmap::from_png_file("assets/heightmap.png")?;
+/// generator.noise.mode = TerrainNoiseMode::Custom(TerrainCustomSource::new(heightmap));
+/// ```
+#[derive(Clone)]
+pub struct TerrainHeightmap {
+    width: usize,
+    height: usize,
+    /// Heights in 0.0 - 1.0, row by row
+    data: Arc<Vec<f32>>,
+    /// World position of the first texel
+    pub origin: Vec2,
+    /// World units per texel
+    pub texel_size: f32,
+    pub wrap: HeightmapWrap,
+}
+
+/// What to sample outside the heightmap
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeightmapWrap {
+    /// Repeat the edge texels
+    Clamp,
+    /// Repeat the whole heightmap
+    Tile,
+}
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    /// The data doesn't match the given size
+    Size { expected: usize, found: usize },
+    /// The bevy image uses a texture format we can't read heights from
+    Format(TextureFormat),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(e) => write!(f, "failed to read heightmap: {e}"),
+            HeightmapError::Image(e) => write!(f, "failed to decode heightmap: {e}"),
+            HeightmapError::Size { expected, found } => {
+                write!(f, "heightmap size mismatch, expected {expected} texels, found {found}")
+            }
+            HeightmapError::Format(format) => {
+                write!(f, "unsupported heightmap texture format {format:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+impl From<std::io::Error> for HeightmapError {
+    fn from(e: std::io::Error) -> Self {
+        HeightmapError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for HeightmapError {
+    fn from(e: image::ImageError) -> Self {
+        HeightmapError::Image(e)
+    }
+}
+
+impl TerrainHeightmap {
+    /// Creates a heightmap from heights in 0.0 - 1.0, row by row, centered on the world origin with one texel per world unit
+    pub fn new(width: usize, height: usize, data: Vec<f32>) -> Result<Self, HeightmapError> {
+        if data.len() != width * height || data.is_empty() {
+            return Err(HeightmapError::Size {
+                expected: width * height,
+                found: data.len(),
+            });
+        }
+        Ok(Self {
+            width,
+            height,
+            data: Arc::new(data),
+            origin: -Vec2::new(width as f32, height as f32) / 2.0,
+            texel_size: 1.0,
+            wrap: HeightmapWrap::Clamp,
+        })
+    }
+
+    pub fn with_origin(mut self, origin: Vec2) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn with_texel_size(mut self, texel_size: f32) -> Self {
+        self.texel_size = texel_size;
+        self
+    }
+
+    pub fn with_wrap(mut self, wrap: HeightmapWrap) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Loads an 8 or 16 bit grayscale png, color images use their luminance
+    pub fn from_png_file(path: impl AsRef<Path>) -> Result<Self, HeightmapError> {
+        Self::from_png_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_png_bytes(bytes: &[u8]) -> Result<Self, HeightmapError> {
+        let image = image::load_from_memory_with_format(bytes, image::ImageFormat::Png)?;
+        // 8 bit images are scaled up, so both end up in the same range
+        let image = image.into_luma16();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let data = image
+            .into_raw()
+            .into_iter()
+            .map(|x| x as f32 / u16::MAX as f32)
+            .collect();
+        Self::new(width, height, data)
+    }
+
+    /// Loads a raw file of little endian 16 bit heights
+    pub fn from_r16_file(
+        path: impl AsRef<Path>,
+        width: usize,
+        height: usize,
+    ) -> Result<Self, HeightmapError> {
+        Self::from_r16_bytes(&std::fs::read(path)?, width, height)
+    }
+
+    pub fn from_r16_bytes(bytes: &[u8], width: usize, height: usize) -> Result<Self, HeightmapError> {
+        let data = bytes
+            .chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]) as f32 / u16::MAX as f32)
+            .collect();
+        Self::new(width, height, data)
+    }
+
+    /// Loads a raw file of little endian 32 bit float heights, expected to be in 0.0 - 1.0
+    pub fn from_r32_file(
+        path: impl AsRef<Path>,
+        width: usize,
+        height: usize,
+    ) -> Result<Self, HeightmapError> {
+        Self::from_r32_bytes(&std::fs::read(path)?, width, height)
+    }
+
+    pub fn from_r32_bytes(bytes: &[u8], width: usize, height: usize) -> Result<Self, HeightmapError> {
+        let data = bytes
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        Self::new(width, height, data)
+    }
+
+    /// Reads the heights from a loaded bevy [`Image`] asset, color images use the red channel
+    pub fn from_image(image: &Image) -> Result<Self, HeightmapError> {
+        let size = image.texture_descriptor.size;
+        let (width, height) = (size.width as usize, size.height as usize);
+        let format = image.texture_descriptor.format;
+
+        let read_u16 = |x: &[u8]| u16::from_ne_bytes([x[0], x[1]]) as f32 / u16::MAX as f32;
+        let read_f32 = |x: &[u8]| f32::from_ne_bytes([x[0], x[1], x[2], x[3]]);
+        let data: Vec<f32> = match format {
+            TextureFormat::R8Unorm => image.data.iter().map(|x| *x as f32 / 255.0).collect(),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => image
+                .data
+                .chunks_exact(4)
+                .map(|x| x[0] as f32 / 255.0)
+                .collect(),
+            TextureFormat::R16Uint | TextureFormat::R16Unorm => {
+                image.data.chunks_exact(2).map(read_u16).collect()
+            }
+            TextureFormat::Rg16Uint => image.data.chunks_exact(4).map(read_u16).collect(),
+            TextureFormat::Rgba16Uint | TextureFormat::Rgba16Unorm => {
+                image.data.chunks_exact(8).map(read_u16).collect()
+            }
+            TextureFormat::R32Float => image.data.chunks_exact(4).map(read_f32).collect(),
+            TextureFormat::Rgba32Float => image.data.chunks_exact(16).map(read_f32).collect(),
+            format => return Err(HeightmapError::Format(format)),
+        };
+        Self::new(width, height, data)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Height of a texel, wrapping coordinates outside the heightmap
+    pub fn texel(&self, x: i64, y: i64) -> f32 {
+        let (x, y) = match self.wrap {
+            HeightmapWrap::Clamp => (
+                x.clamp(0, self.width as i64 - 1),
+                y.clamp(0, self.height as i64 - 1),
+            ),
+            HeightmapWrap::Tile => (
+                x.rem_euclid(self.width as i64),
+                y.rem_euclid(self.height as i64),
+            ),
+        };
+        self.data[y as usize * self.width + x as usize]
+    }
+
+    /// Bilinearly sampled height at a world-space position
+    pub fn get(&self, world_pos: Vec2) -> f32 {
+        let pos = (world_pos - self.origin) / self.texel_size;
+        let cell = pos.floor();
+        let t = pos - cell;
+        let (x, y) = (cell.x as i64, cell.y as i64);
+
+        let top = util::lerp(self.texel(x, y), self.texel(x + 1, y), t.x);
+        let bottom = util::lerp(self.texel(x, y + 1), self.texel(x + 1, y + 1), t.x);
+        util::lerp(top, bottom, t.y)
+    }
+}
+
+impl TerrainHeightSource for TerrainHeightmap {
+    fn sample(&self, pos: Vec2, _seed: TerrainSeed) -> f32 {
+        self.get(pos)
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BinaryHeap;
+
+use bevy::prelude::*;
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+
+use crate::{
+    util::{self, FloodCell, NEIGHBOURS},
+    NoiseMap,
+};
+
+/// Lakes found by [`LakeDetection`], added to chunk entities when the pass runs
+#[derive(Component, Clone, Default)]
+pub struct TerrainLakes {
+    /// Lake of every sample, the index into `levels` plus one, 0 where there's no lake
+    pub mask: Vec<Vec<u16>>,
+    /// Water level of every lake, in the same units as the heights
+    pub levels: Vec<f32>,
+}
+
+impl TerrainLakes {
+    /// Water level at a sample, `None` if it's not in a lake
+    pub fn level(&self, x: usize, y: usize) -> Option<f32> {
+        match self.mask[x][y] {
+            0 => None,
+            lake => Some(self.levels[lake as usize - 1]),
+        }
+    }
+
+    /// Keeps the samples inside `padding`, dropping and renumbering the lakes that aren't in them
+    pub fn crop(&self, padding: usize, size: usize) -> Self {
+        let mut remap = vec![0u16; self.levels.len() + 1];
+        let mut levels = vec![];
+        let mut mask = vec![vec![0u16; size]; size];
+        for (column, source) in mask.iter_mut().zip(self.mask.iter().skip(padding)) {
+            for (sample, lake) in column.iter_mut().zip(source.iter().skip(padding)) {
+                let lake = *lake as usize;
+                if lake == 0 {
+                    continue;
+                }
+                if remap[lake] == 0 {
+                    levels.push(self.levels[lake - 1]);
+                    remap[lake] = levels.len() as u16;
+                }
+                *sample = remap[lake];
+            }
+        }
+        Self { mask, levels }
+    }
+}
+
+/// Finds the closed basins that would hold water and how high they fill before spilling over
+///
+/// Doesn't change the heights. Water spills out over the edge of the padded map, so basins
+/// wider than the padding can get a different level in the chunks either side of them,
+/// raise the padding if lakes don't line up where chunks meet.
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct LakeDetection {
+    /// Samples around the chunk searched for the rim of the basins
+    #[inspector(min = 0, max = 256, display = NumberDisplay::Slider)]
+    pub padding: usize,
+    /// Shallowest water counted as a lake
+    #[inspector(min = 0.0, max = 0.1, display = NumberDisplay::Slider)]
+    pub min_depth: f32,
+    /// Smallest lake in samples
+    #[inspector(min = 1, max = 1000, display = NumberDisplay::Slider)]
+    pub min_size: usize,
+    /// Lakes with water at or below this are left to the sea
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub sea_level: f32,
+}
+
+impl Default for LakeDetection {
+    fn default() -> Self {
+        Self {
+            padding: 64,
+            min_depth: 0.002,
+            min_size: 4,
+            sea_level: 0.3,
+        }
+    }
+}
+
+impl LakeDetection {
+    pub fn apply(&self, map: &NoiseMap) -> TerrainLakes {
+        let size = map.len();
+        let water = fill_depressions(map);
+
+        let mut lakes = TerrainLakes {
+            mask: vec![vec![0u16; size]; size],
+            levels: vec![],
+        };
+        let is_lake = |x: usize, y: usize| {
+            water[x][y] - map[x][y] > self.min_depth && water[x][y] > self.sea_level
+        };
+
+        // group the flooded samples into lakes, every sample of a lake has the same level
+        let mut visited = vec![vec![false; size]; size];
+        for y in 0..size {
+            for x in 0..size {
+                if visited[x][y] || !is_lake(x, y) {
+                    continue;
+                }
+
+                let mut samples = vec![];
+                let mut on_edge = false;
+                let mut stack = vec![(x, y)];
+                visited[x][y] = true;
+                while let Some((sx, sy)) = stack.pop() {
+                    samples.push((sx, sy));
+                    on_edge |= sx <= 1 || sy <= 1 || sx + 2 >= size || sy + 2 >= size;
+                    for (dx, dy, _) in NEIGHBOURS.iter() {
+                        let Some((nx, ny)) = util::neighbour(sx, sy, *dx, *dy, size) else {
+                            continue;
+                        };
+                        if !visited[nx][ny] && is_lake(nx, ny) {
+                            visited[nx][ny] = true;
+                            stack.push((nx, ny));
+                        }
+                    }
+                }
+
+                // the rim of lakes next to the edge may be further out, the chunk next to it can see more of them
+                if on_edge
+                    || samples.len() < self.min_size
+                    || lakes.levels.len() >= u16::MAX as usize
+                {
+                    continue;
+                }
+                lakes.levels.push(water[x][y]);
+                for (sx, sy) in samples {
+                    lakes.mask[sx][sy] = lakes.levels.len() as u16;
+                }
+            }
+        }
+        lakes
+    }
+}
+
+/// Priority flood, the height water would fill every sample to before spilling over the edge of the map
+pub fn fill_depressions(map: &NoiseMap) -> NoiseMap {
+    let size = map.len();
+    let mut water = map.clone();
+    let mut closed = vec![vec![false; size]; size];
+    let mut queue = BinaryHeap::new();
+
+    // water drains off the edges
+    for x in 0..size {
+        for y in 0..size {
+            if x == 0 || y == 0 || x == size - 1 || y == size - 1 {
+                closed[x][y] = true;
+                queue.push(FloodCell {
+                    height: map[x][y],
+                    cell: IVec2::new(x as i32, y as i32),
+                });
+            }
+        }
+    }
+
+    // grow inwards from the lowest sample, anything lower than where it came from is filled up
+    while let Some(FloodCell { height, cell }) = queue.pop() {
+        let (x, y) = (cell.x as usize, cell.y as usize);
+        for (dx, dy, _) in NEIGHBOURS.iter() {
+            let Some((nx, ny)) = util::neighbour(x, y, *dx, *dy, size) else {
+                continue;
+            };
+            if closed[nx][ny] {
+                continue;
+            }
+            closed[nx][ny] = true;
+            water[nx][ny] = map[nx][ny].max(height);
+            queue.push(FloodCell {
+                height: water[nx][ny],
+                cell: IVec2::new(nx as i32, ny as i32),
+            });
+        }
+    }
+    water
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flat map at 0.6 with a square basin at 0.4 inside `min..=max`, walled in at 0.9
+    /// but for a gap at 0.7 in the middle of its left side
+    fn basin(size: usize, min: usize, max: usize) -> NoiseMap {
+        let mut map = vec![vec![0.6; size]; size];
+        for (x, column) in map.iter_mut().enumerate().take(max + 1).skip(min) {
+            for (y, height) in column.iter_mut().enumerate().take(max + 1).skip(min) {
+                let wall = x == min || y == min || x == max || y == max;
+                *height = if wall { 0.9 } else { 0.4 };
+            }
+        }
+        map[min][(min + max) / 2] = 0.7;
+        map
+    }
+
+    #[test]
+    fn basins_fill_to_their_spill_height() {
+        let map = basin(12, 3, 8);
+        let water = fill_depressions(&map);
+        for x in 0..12 {
+            for y in 0..12 {
+                let inside = (4..=7).contains(&x) && (4..=7).contains(&y);
+                let expected = if inside { 0.7 } else { map[x][y] };
+                assert_eq!(water[x][y], expected, "water at ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn basin_is_found_as_a_lake() {
+        let lakes = LakeDetection::default().apply(&basin(12, 3, 8));
+        assert_eq!(lakes.levels, vec![0.7]);
+        for x in 0..12 {
+            for y in 0..12 {
+                let inside = (4..=7).contains(&x) && (4..=7).contains(&y);
+                assert_eq!(lakes.mask[x][y], inside as u16, "mask at ({x}, {y})");
+                assert_eq!(lakes.level(x, y), inside.then_some(0.7));
+            }
+        }
+    }
+
+    #[test]
+    fn lakes_on_the_edge_are_dropped() {
+        // the same basin with its left wall on the edge of the map
+        let map = basin(12, 0, 5);
+        assert_eq!(fill_depressions(&map)[2][2], 0.7);
+        let lakes = LakeDetection::default().apply(&map);
+        assert!(lakes.levels.is_empty());
+        assert!(lakes.mask.iter().flatten().all(|lake| *lake == 0));
+    }
+
+    #[test]
+    fn crop_keeps_the_window_and_renumbers_the_lakes() {
+        // lake 1 only outside the window, lake 2 inside and across its edge
+        let mut mask = vec![vec![0u16; 6]; 6];
+        mask[0][0] = 1;
+        mask[2][2] = 2;
+        mask[3][2] = 2;
+        mask[4][2] = 2;
+        let lakes = TerrainLakes {
+            mask,
+            levels: vec![0.5, 0.6],
+        };
+
+        let cropped = lakes.crop(2, 2);
+        assert_eq!(cropped.levels, vec![0.6]);
+        assert_eq!(cropped.mask, vec![vec![1, 0], vec![1, 0]]);
+        assert_eq!(cropped.level(1, 0), Some(0.6));
+        assert_eq!(cropped.level(0, 1), None);
+    }
+}
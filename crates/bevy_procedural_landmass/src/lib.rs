@@ -1,15 +1,32 @@
+mod biome;
 mod chunk;
 mod debug;
 mod egui_helper;
 mod endless;
 mod erosion;
+mod export;
+mod falloff;
 mod generator;
+mod graph;
+mod heightmap;
+mod lake;
 mod noise;
+mod pass;
 mod regions;
+mod river;
+mod seed;
+mod shallow_water;
+mod source;
+mod terrace;
+mod thermal;
 mod util;
 mod water;
 use std::sync::Arc;
 
+use biome::TerrainBiomeMap;
+use lake::TerrainLakes;
+use river::TerrainRiverPaths;
+use shallow_water::TerrainWaterMap;
 use debug::RainPaths;
 use noise::*;
 use regions::*;
@@ -37,6 +54,7 @@ use crate::generator::TerrainTextureMode;
 
 pub mod prelude {
     pub use crate::{
+        biome::*,
         chunk::TerrainChunkBundle,
         debug::{
             TerrainDebugRainMode, TerrainDebugRainPlugin, TerrainDebugWireframePlugin,
@@ -44,9 +62,21 @@ pub mod prelude {
         },
         endless::EndlessTerrain,
         erosion::*,
+        export::*,
+        falloff::*,
         generator::{TerrainGenerator, TerrainSampler},
+        graph::*,
+        heightmap::*,
+        lake::*,
         noise::*,
+        pass::*,
         regions::*,
+        river::*,
+        seed::TerrainSeed,
+        shallow_water::*,
+        source::*,
+        terrace::*,
+        thermal::*,
         util::*,
         ProceduralLandmassPlugin,
         water::*
@@ -61,8 +91,17 @@ impl Plugin for ProceduralLandmassPlugin {
     fn build(&self, app: &mut App) {
         app
             .add_systems(PreUpdate, (update_endless, create_chunks).chain())
-            .add_systems(Update, (update_chunk_visablity, generator_changed).chain())
-            .add_systems(Update, (spawn_chunk_tasks, handle_check_tasks))
+            .add_systems(
+                Update,
+                (
+                    update_chunk_visablity,
+                    update_normalization,
+                    generator_changed,
+                    spawn_chunk_tasks,
+                )
+                    .chain(),
+            )
+            .add_systems(Update, handle_check_tasks)
             .insert_resource(TerrainGenerator::default())
             //.register_type::<TerrainGenerator>()
             .register_type::<EndlessTerrain>()
@@ -70,16 +109,54 @@ impl Plugin for ProceduralLandmassPlugin {
             .register_type::<TerrainRegions>()
             .register_type::<TerrainType>()
             .register_type::<TerrainErosion>()
+            .register_type::<biome::TerrainBiomes>()
+            .register_type::<biome::ClimateBiomes>()
+            .register_type::<biome::TerrainBiome>()
+            .register_type::<falloff::TerrainFalloff>()
+            .register_type::<falloff::FalloffMask>()
+            .register_type::<falloff::FalloffShape>()
+            .register_type::<falloff::FalloffBlend>()
+            .register_type::<pass::TerrainPasses>()
+            .register_type::<pass::TerrainPass>()
+            .register_type::<pass::TerrainPassMode>()
+            .register_type::<pass::TerrainCustomPass>()
+            .register_type::<pass::BlurPass>()
             .register_type::<TerrainCurve>()
             .register_type::<TerrainCurveMode>()
+            .register_type::<TerrainSpline>()
+            .register_type::<SplineInterpolation>()
+            .register_type::<seed::TerrainSeed>()
             .register_type::<TerrainNoise>()
+            .register_type::<terrace::TerrainTerrace>()
+            .register_type::<terrace::TerraceSteps>()
             .register_type::<TerrainNoiseMode>()
+            .register_type::<TerrainWarp>()
+            .register_type::<DomainWarp>()
+            .register_type::<TerrainNormalization>()
+            .register_type::<EstimatedNormalization>()
+            .register_type::<source::TerrainCustomSource>()
             .register_type::<FMBSimplex>()
-            .register_type::<HydraulicErosion>();
+            .register_type::<RidgedMultifractal>()
+            .register_type::<Billow>()
+            .register_type::<OctaveNoise>()
+            .register_type::<NoiseOctave>()
+            .register_type::<OctaveSource>()
+            .register_type::<Cellular>()
+            .register_type::<CellularDistance>()
+            .register_type::<CellularOutput>()
+            .register_type::<graph::TerrainNoiseGraph>()
+            .register_type::<graph::TerrainNoiseNode>()
+            .register_type::<graph::TerrainGraphSource>()
+            .register_type::<HydraulicErosion>()
+            .register_type::<thermal::ThermalErosion>()
+            .register_type::<shallow_water::ShallowWaterErosion>()
+            .register_type::<river::RiverNetwork>()
+            .register_type::<lake::LakeDetection>();
 
         // add custom renders
-        //let type_registry = app.world.resource::<AppTypeRegistry>();
-        //noise::egui::register_ui(type_registry);
+        let type_registry = app.world.resource::<AppTypeRegistry>();
+        noise::egui::register_ui(type_registry);
+        pass::register_ui(type_registry);
     }
 }
 
@@ -92,6 +169,28 @@ fn update_endless(mut query: Query<&mut EndlessTerrain>, generator: Res<TerrainG
     }
 }
 
+/// Estimates the normalization again when the noise settings it depends on change, runs before the
+/// chunks are marked for regeneration. An edit made later in the frame is picked up next frame and
+/// marks the chunks again.
+fn update_normalization(
+    mut generator: ResMut<TerrainGenerator>,
+    mut estimated_for: Local<Option<(TerrainNoise, biome::TerrainBiomes)>>,
+) {
+    if !generator.is_changed() {
+        return;
+    }
+    // custom sources can't be compared, so they are always estimated again
+    let unchanged = estimated_for.as_ref().is_some_and(|(noise, biomes)| {
+        noise.reflect_partial_eq(&generator.noise) == Some(true)
+            && biomes.reflect_partial_eq(&generator.biomes) == Some(true)
+    });
+    if unchanged {
+        return;
+    }
+    generator.update_normalization();
+    *estimated_for = Some((generator.noise.clone(), generator.biomes.clone()));
+}
+
 fn generator_changed(generator: Res<TerrainGenerator>, mut query: Query<&mut TerrainChunk>) {
     if generator.is_changed() {
         for mut chunk in query.iter_mut() {
@@ -175,6 +274,11 @@ struct ComputeResult {
     noise_map: NoiseMap,
     world_scale: f32,
     rain_paths: Option<Vec<Vec<Vec3>>>,
+    biome_map: Option<biome::BiomeMap>,
+    water_map: Option<NoiseMap>,
+    erosion_maps: Option<TerrainErosionMaps>,
+    rivers: Option<Vec<river::RiverPath>>,
+    lakes: Option<TerrainLakes>,
 }
 
 #[derive(Component)]
@@ -197,21 +301,23 @@ fn spawn_chunk_tasks(
         let generator = generator_arc.clone();
 
         let task = thread_pool.spawn(async move {
-            // create noise map
-            #[allow(unused_mut)]
-            let mut noise_map = generator.generate_noise_map(chunk.position);
+            // create noise map and run the passes on it
+            let (noise_map, output) = generator.generate_passes(chunk.position);
 
-            let rain_paths = generator.generate_erosion(&mut noise_map);
+            // biomes use the eroded heights, so the colors follow the final terrain
+            let biome_map = generator.generate_biome_map(chunk.position, &noise_map);
 
             // create image
             let image_data = match generator.texture_mode {
-                TerrainTextureMode::Color => generator.generate_color_map_image(&noise_map),
+                TerrainTextureMode::Color => {
+                    generator.generate_color_map_image(&noise_map, biome_map.as_ref())
+                }
                 TerrainTextureMode::HeightMap => generator.generate_height_map_image(&noise_map),
             };
             let mut image = Image::new(
                 Extent3d {
-                    width: generator.chunk_size as u32,
-                    height: generator.chunk_size as u32,
+                    width: noise_map.len() as u32,
+                    height: noise_map.len() as u32,
                     depth_or_array_layers: 1,
                 },
                 TextureDimension::D2,
@@ -232,7 +338,12 @@ fn spawn_chunk_tasks(
                 mesh,
                 noise_map,
                 world_scale: generator.world_scale,
-                rain_paths,
+                rain_paths: output.rain_paths,
+                biome_map,
+                water_map: output.water_map,
+                erosion_maps: output.erosion_maps,
+                rivers: output.rivers,
+                lakes: output.lakes,
             }
         });
         commands.entity(e).insert(ComputeChunk(task));
@@ -282,10 +393,30 @@ fn handle_check_tasks(
             // update mesh
             *mesh = meshes.add(result.mesh);
 
-            // TODO: remove this, using it to debug right now
-            if let Some(paths) = result.rain_paths {
-                commands.entity(e).insert(RainPaths(paths));
-            }
+            match result.rain_paths {
+                Some(paths) => commands.entity(e).insert(RainPaths(paths)),
+                None => commands.entity(e).remove::<RainPaths>(),
+            };
+            match result.biome_map {
+                Some(biome_map) => commands.entity(e).insert(TerrainBiomeMap(biome_map)),
+                None => commands.entity(e).remove::<TerrainBiomeMap>(),
+            };
+            match result.water_map {
+                Some(water_map) => commands.entity(e).insert(TerrainWaterMap(water_map)),
+                None => commands.entity(e).remove::<TerrainWaterMap>(),
+            };
+            match result.erosion_maps {
+                Some(erosion_maps) => commands.entity(e).insert(erosion_maps),
+                None => commands.entity(e).remove::<TerrainErosionMaps>(),
+            };
+            match result.rivers {
+                Some(rivers) => commands.entity(e).insert(TerrainRiverPaths(rivers)),
+                None => commands.entity(e).remove::<TerrainRiverPaths>(),
+            };
+            match result.lakes {
+                Some(lakes) => commands.entity(e).insert(lakes),
+                None => commands.entity(e).remove::<TerrainLakes>(),
+            };
 
             // Update AABB
             // Hack: See https://github.com/bevyengine/bevy/issues/4294
@@ -296,3 +427,39 @@ fn handle_check_tasks(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalization_is_estimated_when_the_noise_changes() {
+        let mut app = App::new();
+        app.insert_resource(TerrainGenerator {
+            noise: TerrainNoise {
+                normalization: TerrainNormalization::Estimated(EstimatedNormalization {
+                    samples: 64,
+                    ..default()
+                }),
+                ..default()
+            },
+            ..default()
+        })
+        .add_systems(Update, update_normalization);
+        let range = |app: &App| app.world.resource::<TerrainGenerator>().noise.estimated_range;
+
+        app.update();
+        assert!(range(&app).is_some());
+
+        // settings the noise doesn't depend on leave the range alone
+        let marker = Some(Vec2::new(-5.0, 5.0));
+        app.world.resource_mut::<TerrainGenerator>().noise.estimated_range = marker;
+        app.world.resource_mut::<TerrainGenerator>().height_multiplier = 0.5;
+        app.update();
+        assert_eq!(range(&app), marker);
+
+        app.world.resource_mut::<TerrainGenerator>().noise.scale = 100.0;
+        app.update();
+        assert!(range(&app).is_some_and(|range| Some(range) != marker));
+    }
+}
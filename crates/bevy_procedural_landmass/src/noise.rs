@@ -3,7 +3,10 @@ use bevy::prelude::*;
 use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
 use noisy_bevy::{fbm_simplex_2d_seeded, simplex_noise_2d_seeded};
 
-use crate::util;
+use crate::{
+    graph::TerrainNoiseGraph, seed::TerrainSeed, source::TerrainCustomSource,
+    terrace::TerrainTerrace, util,
+};
 
 
 
@@ -12,42 +15,251 @@ use crate::util;
 pub struct TerrainNoise {
     pub mode: TerrainNoiseMode,
 
-    #[inspector(min = 0.01, max = 100.0, display = NumberDisplay::Slider)]
+    /// Size of the noise features in world units, independent of the chunk size
+    #[inspector(min = 1.0, max = 5000.0, display = NumberDisplay::Slider)]
     pub scale: f32,
+    /// Offset in world units
     pub offset: Vec2,
-    pub seed: f32,
+    pub seed: TerrainSeed,
+    /// Distorts the sample position before the noise is sampled
+    pub warp: TerrainWarp,
+    /// How the raw noise is remapped to 0.0 - 1.0
+    pub normalization: TerrainNormalization,
+    /// Range found by [`TerrainNoise::update_normalization`]
+    #[reflect(ignore)]
+    pub estimated_range: Option<Vec2>,
     /// Curve applied to the noise to allow for more control over the terrain
     pub correction: TerrainCurve,
+    /// Steps applied after the correction, before erosion
+    pub terrace: TerrainTerrace,
 }
 
 impl Default for TerrainNoise {
     fn default() -> Self {
         Self {
             mode: TerrainNoiseMode::default(),            
-            scale: 0.7,
+            scale: 350.0,
             offset: Vec2::ZERO,
-            seed: 0.0,
+            seed: TerrainSeed::default(),
+            warp: TerrainWarp::default(),
+            normalization: TerrainNormalization::default(),
+            estimated_range: None,
             correction: TerrainCurve::default(),
+            terrace: TerrainTerrace::default(),
         }
     }
 }
 
 impl TerrainNoise {
-    pub fn get(&self, pos: Vec2, seed: f32) -> f32 {
-        let height = match &self.mode {
-            TerrainNoiseMode::FMBSimplex(x) => x.get(pos, seed),
-            TerrainNoiseMode::Simplex => simplex_noise_2d_seeded(pos, seed),
+    /// Samples the noise at a world-space position
+    pub fn get(&self, world_pos: Vec2) -> f32 {
+        let height = self.get_raw(world_pos);
+        let height = match self.normalization_range() {
+            Some(range) => util::remap(height, range.x, range.y, 0.0, 1.0),
+            None => height,
+        };
+        let height = self.correction.get(height);
+        self.terrace.apply(height, world_pos, self.seed)
+    }
+
+    /// Samples the noise at a world-space position, without normalization or correction
+    pub fn get_raw(&self, world_pos: Vec2) -> f32 {
+        let world_pos = self.warp.apply(world_pos + self.offset, self.scale, self.seed);
+        match &self.mode {
+            // custom sources work in world units, so skip the scale
+            TerrainNoiseMode::Custom(x) => x.sample(world_pos, self.seed),
+            mode => mode.get_raw(world_pos / self.scale, self.seed),
+        }
+    }
+
+    /// Range remapped to 0.0 - 1.0, the same for every chunk
+    pub fn normalization_range(&self) -> Option<Vec2> {
+        match &self.normalization {
+            TerrainNormalization::Analytic => Some(self.mode.range()),
+            // fall back to the analytic range until it has been estimated
+            TerrainNormalization::Estimated(_) => {
+                Some(self.estimated_range.unwrap_or_else(|| self.mode.range()))
+            }
+            TerrainNormalization::None => None,
+        }
+    }
+
+    /// Estimates the range of the noise by sampling random points, needs to be called after
+    /// the noise settings change, the plugin does this for the [`TerrainGenerator`](crate::generator::TerrainGenerator) resource
+    pub fn update_normalization(&mut self) {
+        self.estimated_range = match &self.normalization {
+            TerrainNormalization::Estimated(x) => Some(x.estimate(self)),
+            _ => None,
         };
-        self.correction.get(height)
     }
 }
 
+#[derive(Clone, Default, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum TerrainNormalization {
+    /// Approximate range worked out from the noise settings
+    #[default]
+    Analytic,
+    /// Range measured by sampling the noise at random points
+    Estimated(EstimatedNormalization),
+    /// Use the raw noise
+    None,
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct EstimatedNormalization {
+    #[inspector(min = 16, max = 100_000)]
+    pub samples: usize,
+    /// Points are picked within this distance of the world origin
+    #[inspector(min = 1.0, max = 1_000_000.0)]
+    pub extent: f32,
+}
+
+impl Default for EstimatedNormalization {
+    fn default() -> Self {
+        Self {
+            samples: 4096,
+            extent: 10_000.0,
+        }
+    }
+}
+
+impl EstimatedNormalization {
+    pub fn estimate(&self, noise: &TerrainNoise) -> Vec2 {
+        // own rng so the points only depend on the seed
+        let mut rng = fastrand::Rng::with_seed(noise.seed.derive("normalization").into());
+        let mut range = Vec2::new(f32::MAX, f32::MIN);
+        for _ in 0..self.samples {
+            let pos = Vec2::new(rng.f32(), rng.f32()) * 2.0 - Vec2::ONE;
+            let height = noise.get_raw(pos * self.extent);
+            range.x = range.x.min(height);
+            range.y = range.y.max(height);
+        }
+
+        if range.x < range.y {
+            range
+        } else {
+            // no samples or flat noise, avoid dividing by zero
+            noise.mode.range()
+        }
+    }
+}
+
+
+#[derive(Clone, Default, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum TerrainWarp {
+    #[default]
+    None,
+    Domain(DomainWarp),
+}
+
+impl TerrainWarp {
+    /// Offsets a world-space position, `scale` is the [`TerrainNoise::scale`]
+    pub fn apply(&self, world_pos: Vec2, scale: f32, seed: TerrainSeed) -> Vec2 {
+        match self {
+            TerrainWarp::None => world_pos,
+            TerrainWarp::Domain(x) => x.apply(world_pos, scale, seed),
+        }
+    }
+}
+
+/// Offsets the sample position by a low frequency fbm field, repeated for each iteration
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct DomainWarp {
+    /// How far to move the position, relative to the noise scale
+    #[inspector(min = 0.0, max = 4.0, display = NumberDisplay::Slider)]
+    pub strength: f32,
+    #[inspector(min = 1, max = 5, display = NumberDisplay::Slider)]
+    pub iterations: usize,
+    /// Frequency of the warp field, relative to the noise scale
+    #[inspector(min = 0.01, max = 4.0, display = NumberDisplay::Slider)]
+    pub frequency: f32,
+    #[inspector(min = 1, max = 6, display = NumberDisplay::Slider)]
+    pub octaves: usize,
+}
+
+impl Default for DomainWarp {
+    fn default() -> Self {
+        Self {
+            strength: 0.5,
+            iterations: 1,
+            frequency: 0.5,
+            octaves: 3,
+        }
+    }
+}
+
+impl DomainWarp {
+    pub fn apply(&self, world_pos: Vec2, scale: f32, seed: TerrainSeed) -> Vec2 {
+        let mut world_pos = world_pos;
+        for i in 0..self.iterations {
+            // keep the warp field seperate from the terrain and each iteration
+            let warp_seed = seed.derive("warp").layer(i as u64).noise_seed();
+            let pos = world_pos / scale * self.frequency;
+            let offset = Vec2::new(
+                fbm_simplex_2d_seeded(pos, self.octaves, 2.0, 0.5, warp_seed),
+                fbm_simplex_2d_seeded(pos + Vec2::new(5.2, 1.3), self.octaves, 2.0, 0.5, warp_seed),
+            );
+            world_pos += offset * self.strength * scale;
+        }
+        world_pos
+    }
+}
 
 #[derive(Clone, Reflect, InspectorOptions)]
 #[reflect(InspectorOptions)]
 pub enum TerrainNoiseMode {
     FMBSimplex(FMBSimplex),
+    RidgedMultifractal(RidgedMultifractal),
+    Billow(Billow),
+    /// Fbm with settings for every octave, see [`OctaveNoise`]
+    Octaves(OctaveNoise),
+    Cellular(Cellular),
     Simplex,
+    /// Combines several noise sources, see [`TerrainNoiseGraph`]
+    Graph(TerrainNoiseGraph),
+    /// User provided [`TerrainHeightSource`](crate::source::TerrainHeightSource)
+    Custom(TerrainCustomSource),
+}
+
+impl TerrainNoiseMode {
+    /// Noise value remapped to 0.0 - 1.0 using the approximate [`TerrainNoiseMode::range`]
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let range = self.range();
+        util::remap(self.get_raw(pos, seed), range.x, range.y, 0.0, 1.0)
+    }
+
+    /// Noise value before any normalization
+    pub fn get_raw(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        match self {
+            TerrainNoiseMode::FMBSimplex(x) => x.get_raw(pos, seed),
+            TerrainNoiseMode::RidgedMultifractal(x) => x.get_raw(pos, seed),
+            TerrainNoiseMode::Billow(x) => x.get_raw(pos, seed),
+            TerrainNoiseMode::Octaves(x) => x.get_raw(pos, seed),
+            TerrainNoiseMode::Cellular(x) => x.get(pos, seed),
+            TerrainNoiseMode::Simplex => simplex_noise_2d_seeded(pos, seed.noise_seed()),
+            TerrainNoiseMode::Graph(x) => x.get(pos, seed),
+            TerrainNoiseMode::Custom(x) => x.sample(pos, seed),
+        }
+    }
+
+    /// Approximate min and max of [`TerrainNoiseMode::get_raw`]
+    pub fn range(&self) -> Vec2 {
+        match self {
+            TerrainNoiseMode::FMBSimplex(x) => x.range(),
+            TerrainNoiseMode::RidgedMultifractal(x) => x.range(),
+            TerrainNoiseMode::Billow(x) => x.range(),
+            TerrainNoiseMode::Octaves(x) => x.range(),
+            TerrainNoiseMode::Simplex => Vec2::new(-1.0, 1.0),
+            // these are already in 0.0 - 1.0
+            TerrainNoiseMode::Cellular(_)
+            | TerrainNoiseMode::Graph(_)
+            | TerrainNoiseMode::Custom(_) => Vec2::new(0.0, 1.0),
+        }
+    }
 }
 
 impl Default for TerrainNoiseMode {
@@ -78,14 +290,349 @@ impl Default for FMBSimplex {
 }
 
 impl FMBSimplex {
-    pub fn get(&self, pos: Vec2, seed: f32) -> f32 {
-        let height = fbm_simplex_2d_seeded(pos, self.octaves, self.lacunarity, self.gain, seed);
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let range = self.range();
+        util::remap(self.get_raw(pos, seed), range.x, range.y, 0.0, 1.0)
+    }
+
+    pub fn get_raw(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        fbm_simplex_2d_seeded(pos, self.octaves, self.lacunarity, self.gain, seed.noise_seed())
+    }
 
-        // Clamp height to 0.0 - 1.0, approximate min and max for fbm with simplex noise given octaves, gain, and initial amplitude of 1.
+    /// Approximate min and max for fbm with simplex noise given octaves, gain, and initial amplitude of 1.
+    pub fn range(&self) -> Vec2 {
         let approx_max = (1.0 - self.gain.powf(self.octaves as f32)) / (1.0 - self.gain);
-        let approx_min = -approx_max;
+        Vec2::new(-approx_max, approx_max)
+    }
+}
+
+/// Fractal noise built from inverted absolute simplex, gives sharp ridges
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct RidgedMultifractal {
+    #[inspector(min = 1, max = 10, display = NumberDisplay::Slider)]
+    pub octaves: usize,
+    #[inspector(min = 1.0, max = 10.0, display = NumberDisplay::Slider)]
+    pub lacunarity: f32,
+    #[inspector(min = 0.01, max = 0.9, display = NumberDisplay::Slider)]
+    pub gain: f32,
+}
+
+impl Default for RidgedMultifractal {
+    fn default() -> Self {
+        Self {
+            octaves: 6,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+impl RidgedMultifractal {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let range = self.range();
+        util::remap(self.get_raw(pos, seed), range.x, range.y, 0.0, 1.0)
+    }
+
+    pub fn get_raw(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let mut height = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        // each octave is weighted by the previous one, so detail only shows up on the ridges
+        let mut weight = 1.0;
+        let seed = seed.noise_seed();
+
+        for _ in 0..self.octaves {
+            let mut signal = 1.0 - simplex_noise_2d_seeded(pos * frequency, seed).abs();
+            signal *= signal;
+            signal *= weight;
+            weight = (signal * 2.0).clamp(0.0, 1.0);
+
+            height += signal * amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        height
+    }
+
+    /// Every octave is in 0.0 - 1.0, so the max is the sum of the amplitudes
+    pub fn range(&self) -> Vec2 {
+        let approx_max = (1.0 - self.gain.powf(self.octaves as f32)) / (1.0 - self.gain);
+        Vec2::new(0.0, approx_max)
+    }
+}
+
+/// Fractal noise built from absolute simplex, gives rounded hills
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct Billow {
+    #[inspector(min = 1, max = 10, display = NumberDisplay::Slider)]
+    pub octaves: usize,
+    #[inspector(min = 1.0, max = 10.0, display = NumberDisplay::Slider)]
+    pub lacunarity: f32,
+    #[inspector(min = 0.01, max = 0.9, display = NumberDisplay::Slider)]
+    pub gain: f32,
+}
+
+impl Default for Billow {
+    fn default() -> Self {
+        Self {
+            octaves: 6,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+impl Billow {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let range = self.range();
+        util::remap(self.get_raw(pos, seed), range.x, range.y, 0.0, 1.0)
+    }
+
+    pub fn get_raw(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let mut height = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let seed = seed.noise_seed();
+
+        for _ in 0..self.octaves {
+            let signal = simplex_noise_2d_seeded(pos * frequency, seed).abs() * 2.0 - 1.0;
+            height += signal * amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        height
+    }
+
+    /// Same range as fbm, every octave is in -1.0 - 1.0
+    pub fn range(&self) -> Vec2 {
+        let approx_max = (1.0 - self.gain.powf(self.octaves as f32)) / (1.0 - self.gain);
+        Vec2::new(-approx_max, approx_max)
+    }
+}
+
+/// Fbm where every octave is set up by hand, e.g. a large continent octave plus a small detail octave
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct OctaveNoise {
+    pub octaves: Vec<NoiseOctave>,
+}
+
+impl Default for OctaveNoise {
+    /// Same shape as the default [`FMBSimplex`]
+    fn default() -> Self {
+        let fbm = FMBSimplex::default();
+        Self {
+            octaves: (0..fbm.octaves)
+                .map(|i| NoiseOctave {
+                    frequency: fbm.lacunarity.powi(i as i32),
+                    amplitude: fbm.gain.powi(i as i32),
+                    ..default()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl OctaveNoise {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        let range = self.range();
+        util::remap(self.get_raw(pos, seed), range.x, range.y, 0.0, 1.0)
+    }
+
+    pub fn get_raw(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        // every octave gets its own seed, so octaves with the same frequency don't line up
+        self.octaves
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x.get(pos, seed.layer(i as u64)))
+            .sum()
+    }
+
+    /// Sum of the range of every octave scaled by its amplitude
+    pub fn range(&self) -> Vec2 {
+        let range = self.octaves.iter().fold(Vec2::ZERO, |range, octave| {
+            let octave_range = octave.source.range() * octave.amplitude;
+            // negative amplitudes flip the range
+            range + Vec2::new(octave_range.min_element(), octave_range.max_element())
+        });
+        if range.x < range.y {
+            range
+        } else {
+            // no octaves, avoid dividing by zero
+            Vec2::new(0.0, 1.0)
+        }
+    }
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct NoiseOctave {
+    /// Multiplies the sample position, relative to [`TerrainNoise::scale`]
+    #[inspector(min = 0.001, max = 1000.0)]
+    pub frequency: f32,
+    pub amplitude: f32,
+    /// Offset applied after the frequency, so octaves don't line up
+    pub offset: Vec2,
+    pub source: OctaveSource,
+}
+
+impl Default for NoiseOctave {
+    fn default() -> Self {
+        Self {
+            frequency: 1.0,
+            amplitude: 1.0,
+            offset: Vec2::ZERO,
+            source: OctaveSource::default(),
+        }
+    }
+}
+
+impl NoiseOctave {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        self.source.get(pos * self.frequency + self.offset, seed) * self.amplitude
+    }
+}
+
+/// Noise sampled by a single [`NoiseOctave`]
+#[derive(Clone, Default, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum OctaveSource {
+    #[default]
+    Simplex,
+    /// Inverted absolute simplex, like a single octave of [`RidgedMultifractal`]
+    Ridged,
+    /// Absolute simplex, like a single octave of [`Billow`]
+    Billow,
+    Cellular(Cellular),
+}
+
+impl OctaveSource {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        match self {
+            OctaveSource::Simplex => simplex_noise_2d_seeded(pos, seed.noise_seed()),
+            OctaveSource::Ridged => {
+                let signal = 1.0 - simplex_noise_2d_seeded(pos, seed.noise_seed()).abs();
+                signal * signal
+            }
+            OctaveSource::Billow => {
+                simplex_noise_2d_seeded(pos, seed.noise_seed()).abs() * 2.0 - 1.0
+            }
+            OctaveSource::Cellular(x) => x.get(pos, seed),
+        }
+    }
 
-        util::remap(height, approx_min, approx_max, 0.0, 1.0)
+    pub fn range(&self) -> Vec2 {
+        match self {
+            OctaveSource::Simplex | OctaveSource::Billow => Vec2::new(-1.0, 1.0),
+            OctaveSource::Ridged | OctaveSource::Cellular(_) => Vec2::new(0.0, 1.0),
+        }
+    }
+}
+
+/// Worley noise, distance to random feature points, one per grid cell
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct Cellular {
+    pub distance: CellularDistance,
+    pub output: CellularOutput,
+    /// How far feature points can move from the center of their cell, at 1.0 they move up to 0.5
+    /// along each axis, so they can be anywhere in the cell
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub jitter: f32,
+}
+
+impl Default for Cellular {
+    fn default() -> Self {
+        Self {
+            distance: CellularDistance::Euclidean,
+            output: CellularOutput::F1,
+            jitter: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Reflect)]
+pub enum CellularDistance {
+    Euclidean,
+    Manhattan,
+    Chebyshev,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Reflect)]
+pub enum CellularOutput {
+    /// Distance to the closest point
+    F1,
+    /// Distance to the second closest point
+    F2,
+    /// Gives cracks along the cell edges
+    F2MinusF1,
+    /// Random value per cell, gives flat plateaus
+    CellValue,
+}
+
+impl Cellular {
+    pub fn get(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        // points stay inside their cell, offset at most 0.5 from the center on each axis. Of the point
+        // in our cell and the ones in the closer cells beside it along x and y, two are always within
+        // 2.0 in every metric, while points 3 cells away are at least 2.0 away along one axis. So a
+        // reach of 2 (5x5 cells) always finds the closest two, a reach of 1 misses them near the corners.
+        let (f1, f2, f1_hash) = self.closest(pos, seed, 2);
+
+        // Clamp to 0.0 - 1.0, approximate max distance for each metric with full jitter
+        let approx_max = match self.distance {
+            CellularDistance::Euclidean => 1.0,
+            CellularDistance::Manhattan => 1.4,
+            CellularDistance::Chebyshev => 0.8,
+        };
+
+        let height = match self.output {
+            CellularOutput::F1 => f1 / approx_max,
+            CellularOutput::F2 => f2 / (approx_max * 1.5),
+            CellularOutput::F2MinusF1 => (f2 - f1) / approx_max,
+            CellularOutput::CellValue => util::hash_to_unit(util::hash_2d(0, 0, f1_hash)),
+        };
+        height.clamp(0.0, 1.0)
+    }
+
+    /// Distance to the closest two points in the cells up to `reach` away, and the hash of the closest
+    fn closest(&self, pos: Vec2, seed: TerrainSeed, reach: i32) -> (f32, f32, u32) {
+        let seed = seed.to_u32();
+        let cell = pos.floor();
+        let (cell_x, cell_y) = (cell.x as i32, cell.y as i32);
+
+        let mut f1 = f32::MAX;
+        let mut f2 = f32::MAX;
+        let mut f1_hash = 0;
+
+        for y in -reach..=reach {
+            for x in -reach..=reach {
+                let (px, py) = (cell_x + x, cell_y + y);
+                let hash = util::hash_2d(px, py, seed);
+                let jitter = Vec2::new(
+                    util::hash_to_unit(hash),
+                    util::hash_to_unit(util::hash_2d(px, py, hash)),
+                ) - 0.5;
+                let point = Vec2::new(px as f32, py as f32) + 0.5 + jitter * self.jitter;
+
+                let delta = point - pos;
+                let dist = match self.distance {
+                    CellularDistance::Euclidean => delta.length(),
+                    CellularDistance::Manhattan => delta.x.abs() + delta.y.abs(),
+                    CellularDistance::Chebyshev => delta.x.abs().max(delta.y.abs()),
+                };
+
+                if dist < f1 {
+                    f2 = f1;
+                    f1 = dist;
+                    f1_hash = hash;
+                } else if dist < f2 {
+                    f2 = dist;
+                }
+            }
+        }
+        (f1, f2, f1_hash)
     }
 }
 
@@ -94,9 +641,10 @@ pub mod egui {
     use std::any::{Any, TypeId};
 
     use crate::{egui_helper::many_unimplemented, TerrainCurve, util};
+    use super::{TerrainCurveMode, TerrainSpline};
     use bevy::prelude::*;
     use bevy_inspector_egui::{
-        egui::plot::{Line, Plot, PlotPoints},
+        egui::plot::{Line, Plot, PlotPoint, PlotPoints, PlotResponse, Points},
         inspector_egui_impls::InspectorEguiImpl,
         reflect_inspector::InspectorUi,
         *,
@@ -118,7 +666,7 @@ pub mod egui {
         value: &mut dyn Any,
         ui: &mut egui::Ui,
         _options: &dyn Any,
-        _id: egui::Id,
+        id: egui::Id,
         mut env: InspectorUi<'_, '_>,
     ) -> bool {
         let value = value.downcast_mut::<TerrainCurve>().unwrap();
@@ -155,7 +703,7 @@ pub mod egui {
                     })
                     .collect();
                 let line = Line::new(points);
-                Plot::new("curve")
+                let response = Plot::new(id.with("curve"))
                     .allow_boxed_zoom(false)
                     .allow_scroll(false)
                     .allow_drag(false)
@@ -166,12 +714,83 @@ pub mod egui {
                     .show_x(true)
                     .min_size(egui::Vec2::splat(1.25))
                     .height(100.0)
-                    .show(ui, |plot_ui| plot_ui.line(line));
+                    .show(ui, |plot_ui| {
+                        plot_ui.line(line);
+                        if let TerrainCurveMode::Spline(spline) = &value.mode {
+                            let points: Vec<[f64; 2]> = spline
+                                .points
+                                .iter()
+                                .map(|p| [p.x as f64, p.y as f64])
+                                .collect();
+                            plot_ui.points(Points::new(points).radius(4.0));
+                        }
+                    });
+
+                if let TerrainCurveMode::Spline(spline) = &mut value.mode {
+                    changed |= spline_ui(ui, id, spline, &response);
+                }
             })
         });
         changed
     }
 
+    /// Lets the spline points be dragged around on the plot, returns true if the spline changed
+    fn spline_ui(
+        ui: &mut egui::Ui,
+        id: egui::Id,
+        spline: &mut TerrainSpline,
+        plot: &PlotResponse<()>,
+    ) -> bool {
+        // how close in pixels the pointer has to be to pick a point
+        const PICK_RADIUS: f32 = 8.0;
+
+        let selected_id = id.with("selected_point");
+        let mut selected: Option<usize> = ui.data(|d| d.get_temp(selected_id)).flatten();
+        let mut changed = false;
+
+        let pointer = plot.response.interact_pointer_pos();
+        if plot.response.drag_started() {
+            selected = pointer.and_then(|pos| {
+                spline
+                    .points
+                    .iter()
+                    .enumerate()
+                    .map(|(i, p)| {
+                        let screen = plot.transform.position_from_point(&PlotPoint::new(p.x, p.y));
+                        (i, screen.distance(pos))
+                    })
+                    .filter(|(_, distance)| *distance <= PICK_RADIUS)
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(i, _)| i)
+            });
+        }
+
+        if plot.response.dragged() {
+            if let (Some(index), Some(pos)) = (selected, pointer) {
+                let point = plot.transform.value_from_position(pos);
+                spline.set_point(index, Vec2::new(point.x as f32, point.y as f32));
+                changed = true;
+            }
+        }
+
+        ui.vertical(|ui| {
+            if ui.button("Add").clicked() {
+                selected = Some(spline.add_point());
+                changed = true;
+            }
+            let can_remove = selected.is_some() && spline.points.len() > 2;
+            if ui.add_enabled(can_remove, egui::Button::new("Remove")).clicked() {
+                if let Some(index) = selected.take() {
+                    spline.remove_point(index);
+                    changed = true;
+                }
+            }
+        });
+
+        ui.data_mut(|d| d.insert_temp(selected_id, selected));
+        changed
+    }
+
     fn curve_ui_readonly(
         value: &dyn Any,
         ui: &mut egui::Ui,
@@ -216,13 +835,15 @@ pub enum TerrainCurveMode {
     CubicIn,
     CubicOut,
     CubicInOut,
+    /// User defined curve through control points
+    Spline(TerrainSpline),
 }
 
 impl TerrainCurve {
     pub fn get(&self, height: f32) -> f32 {
 
         // Apply the curve
-        let mut x = match self.mode {
+        let mut x = match &self.mode {
             TerrainCurveMode::Linear => height,
             TerrainCurveMode::SquareIn => height.powi(2),
             TerrainCurveMode::SquareOut => 1.0 - (1.0 - height).powi(2),
@@ -235,6 +856,7 @@ impl TerrainCurve {
                     1.0 - (-2.0 * height + 2.0).powi(3) / 2.0
                 }
             }
+            TerrainCurveMode::Spline(spline) => spline.get(height),
         };
 
         // Apply the offset
@@ -250,3 +872,211 @@ impl TerrainCurve {
         }
     }
 }
+
+/// Curve through control points, the points are kept sorted by x
+#[derive(Clone, Reflect, InspectorOptions, Debug)]
+#[reflect(Default, InspectorOptions)]
+pub struct TerrainSpline {
+    pub points: Vec<Vec2>,
+    pub interpolation: SplineInterpolation,
+}
+
+impl Default for TerrainSpline {
+    fn default() -> Self {
+        Self {
+            points: vec![
+                Vec2::new(0.0, 0.0),
+                Vec2::new(0.4, 0.2),
+                Vec2::new(0.7, 0.6),
+                Vec2::new(1.0, 1.0),
+            ],
+            interpolation: SplineInterpolation::MonotoneCubic,
+        }
+    }
+}
+
+/// Points are compared bit for bit, so a spline always equals itself and [`TerrainCurveMode`] can be [`Eq`]
+impl PartialEq for TerrainSpline {
+    fn eq(&self, other: &Self) -> bool {
+        let bits = |point: &Vec2| point.to_array().map(f32::to_bits);
+        self.interpolation == other.interpolation
+            && self.points.len() == other.points.len()
+            && self.points.iter().zip(&other.points).all(|(a, b)| bits(a) == bits(b))
+    }
+}
+
+impl Eq for TerrainSpline {}
+
+#[derive(Clone, PartialEq, Eq, Reflect, Debug)]
+pub enum SplineInterpolation {
+    /// Never overshoots, so rising points give a rising curve
+    MonotoneCubic,
+    /// Smoother, but can overshoot between points
+    CatmullRom,
+}
+
+impl TerrainSpline {
+    pub fn get(&self, x: f32) -> f32 {
+        let points = &self.points;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return x;
+        };
+
+        // flat outside the points
+        if x <= first.x {
+            return first.y;
+        }
+        if x >= last.x {
+            return last.y;
+        }
+
+        // find the segment x is in
+        let i = points
+            .windows(2)
+            .position(|w| x >= w[0].x && x <= w[1].x)
+            .unwrap_or(0);
+        let (p1, p2) = (points[i], points[i + 1]);
+        let width = p2.x - p1.x;
+        if width <= f32::EPSILON {
+            return p2.y;
+        }
+        let t = (x - p1.x) / width;
+
+        match self.interpolation {
+            SplineInterpolation::MonotoneCubic => {
+                let m1 = self.monotone_tangent(i);
+                let m2 = self.monotone_tangent(i + 1);
+
+                // cubic hermite
+                let t2 = t * t;
+                let t3 = t2 * t;
+                (2.0 * t3 - 3.0 * t2 + 1.0) * p1.y
+                    + (t3 - 2.0 * t2 + t) * width * m1
+                    + (-2.0 * t3 + 3.0 * t2) * p2.y
+                    + (t3 - t2) * width * m2
+            }
+            SplineInterpolation::CatmullRom => {
+                let p0 = points[i.saturating_sub(1)].y;
+                let p3 = points[(i + 2).min(points.len() - 1)].y;
+                let (p1, p2) = (p1.y, p2.y);
+                0.5 * (2.0 * p1
+                    + (p2 - p0) * t
+                    + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+                    + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t)
+            }
+        }
+    }
+
+    /// Tangent at a point using the weighted harmonic mean of the slopes on either side (Fritsch-Butland),
+    /// this keeps the curve monotone between points
+    fn monotone_tangent(&self, i: usize) -> f32 {
+        let points = &self.points;
+        let slope = |a: Vec2, b: Vec2| {
+            let width = b.x - a.x;
+            if width <= f32::EPSILON {
+                0.0
+            } else {
+                (b.y - a.y) / width
+            }
+        };
+
+        if i == 0 {
+            return slope(points[0], points[1]);
+        }
+        if i == points.len() - 1 {
+            return slope(points[i - 1], points[i]);
+        }
+
+        let (d0, d1) = (slope(points[i - 1], points[i]), slope(points[i], points[i + 1]));
+        if d0 * d1 <= 0.0 {
+            // local min or max, keep it flat
+            return 0.0;
+        }
+        let h0 = points[i].x - points[i - 1].x;
+        let h1 = points[i + 1].x - points[i].x;
+        let w1 = 2.0 * h1 + h0;
+        let w2 = h1 + 2.0 * h0;
+        (w1 + w2) / (w1 / d0 + w2 / d1)
+    }
+
+    /// Moves a point, keeping it between its neighbours so the points stay sorted
+    pub fn set_point(&mut self, index: usize, point: Vec2) {
+        if index >= self.points.len() {
+            return;
+        }
+        let min = if index > 0 {
+            self.points[index - 1].x
+        } else {
+            f32::MIN
+        };
+        let max = self.points.get(index + 1).map_or(f32::MAX, |p| p.x);
+        self.points[index] = Vec2::new(point.x.clamp(min, max), point.y);
+    }
+
+    /// Adds a point in the middle of the widest gap, returns its index
+    pub fn add_point(&mut self) -> usize {
+        let Some(i) = (0..self.points.len().saturating_sub(1))
+            .max_by(|a, b| {
+                let width = |i: usize| self.points[i + 1].x - self.points[i].x;
+                width(*a).total_cmp(&width(*b))
+            })
+        else {
+            let point = self.points.last().map_or(Vec2::ZERO, |p| *p + Vec2::X * 0.5);
+            self.points.push(point);
+            return self.points.len() - 1;
+        };
+        let x = (self.points[i].x + self.points[i + 1].x) / 2.0;
+        self.points.insert(i + 1, Vec2::new(x, self.get(x)));
+        i + 1
+    }
+
+    /// Removes a point, always keeping at least two
+    pub fn remove_point(&mut self, index: usize) {
+        if self.points.len() > 2 && index < self.points.len() {
+            self.points.remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cellular_finds_the_closest_points_at_full_jitter() {
+        let seed = TerrainSeed::default();
+        for distance in [
+            CellularDistance::Euclidean,
+            CellularDistance::Manhattan,
+            CellularDistance::Chebyshev,
+        ] {
+            let cellular = Cellular {
+                distance,
+                ..default()
+            };
+            for i in 0..4096 {
+                let pos = Vec2::new((i % 64) as f32, (i / 64) as f32) * 0.37 - 10.0;
+                assert_eq!(
+                    cellular.closest(pos, seed, 2),
+                    cellular.closest(pos, seed, 4),
+                    "{:?} at {pos}",
+                    cellular.distance
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spline_curves_are_eq() {
+        fn assert_eq_impl<T: Eq>(value: &T) -> bool {
+            value == value
+        }
+        let mut spline = TerrainSpline::default();
+        spline.points[1].y = f32::NAN;
+        assert!(assert_eq_impl(&TerrainCurveMode::Spline(spline.clone())));
+
+        let mut other = spline.clone();
+        other.points[2].x = 0.8;
+        assert_ne!(spline, other);
+    }
+}
@@ -0,0 +1,401 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+
+use crate::{
+    erosion::{TerrainErosion, TerrainErosionMaps},
+    falloff::TerrainFalloff,
+    lake::{LakeDetection, TerrainLakes},
+    noise::{TerrainCurve, TerrainNoise},
+    river::{RiverNetwork, RiverPath},
+    seed::TerrainSeed,
+    terrace::TerraceSteps,
+    util, NoiseMap,
+};
+
+/// User defined post-process, plug it into [`TerrainPassMode::Custom`] to run it along with the
+/// built in passes.
+///
+/// Every chunk runs it on its own task after the noise is sampled, with the generator cloned
+/// when the chunk starts, so keep any data it holds behind an `Arc`.
+pub trait TerrainHeightPass: TerrainHeightPassClone + Send + Sync + 'static {
+    /// Changes the heights of a chunk, the map includes [`TerrainPassContext::padding`] extra samples on every side
+    fn apply(&self, map: &mut NoiseMap, context: &TerrainPassContext);
+
+    /// Samples this pass reads past the edge of the chunk, so the chunk edges line up with their neighbours
+    fn padding(&self) -> usize {
+        0
+    }
+}
+
+util::boxed_trait!(
+    /// A [`TerrainHeightPass`] held by [`TerrainPassMode::Custom`], the inspector lists it but can't edit its settings
+    TerrainCustomPass(dyn TerrainHeightPass),
+    TerrainHeightPassClone
+);
+
+impl TerrainCustomPass {
+    pub fn new(pass: impl TerrainHeightPass) -> Self {
+        Self(Box::new(pass))
+    }
+}
+
+/// Where the chunk being processed is in the world
+#[derive(Clone)]
+pub struct TerrainPassContext<'a> {
+    /// Chunk coordinate
+    pub chunk: IVec2,
+    pub chunk_size: usize,
+    pub world_scale: f32,
+    pub height_multiplier: f32,
+    /// World seed, the seed of [`TerrainGenerator::noise`](crate::generator::TerrainGenerator::noise)
+    pub seed: TerrainSeed,
+    /// Extra samples on every side of the map, cropped off after the last pass
+    pub padding: usize,
+    /// World position of `map[0][0]`
+    pub origin: Vec2,
+    /// Sample of `map[0][0]` counted from the world origin, the same sample has the same coordinate in every chunk
+    pub origin_texel: IVec2,
+    /// World units between samples
+    pub texel_size: f32,
+    /// Noise the map was made from, for passes that need heights further out than the padding
+    pub noise: &'a TerrainNoise,
+    /// Passes run on the map before the current one, enabled or not
+    pub earlier_passes: &'a [TerrainPass],
+}
+
+impl TerrainPassContext<'_> {
+    /// World-space position of a map sample, the same as [`TerrainGenerator::texel_world_position`](crate::generator::TerrainGenerator::texel_world_position)
+    pub fn world_position(&self, x: usize, y: usize) -> Vec2 {
+        let texel = self.origin_texel + IVec2::new(x as i32, y as i32);
+        util::texel_world_position(texel, self.chunk_size, self.world_scale)
+    }
+
+    /// Height anywhere in the world as the current pass would see it, the noise with the earlier
+    /// passes that only look at one sample applied. Passes that need the samples around them, like
+    /// erosion or blur, only change the heights a little and are skipped.
+    pub fn height_at(&self, pos: Vec2) -> f32 {
+        self.earlier_passes
+            .iter()
+            .filter(|x| x.enabled)
+            .fold(self.noise.get(pos), |height, pass| {
+                pass.mode
+                    .apply_sample(height, pos, self.seed)
+                    .unwrap_or(height)
+            })
+    }
+}
+
+/// Results of the passes besides the heights
+#[derive(Default)]
+pub struct TerrainPassOutput {
+    pub rain_paths: Option<Vec<Vec<Vec3>>>,
+    /// Water depth left by [`ShallowWaterErosion`](crate::shallow_water::ShallowWaterErosion)
+    pub water_map: Option<NoiseMap>,
+    /// Sediment, flow and wetness left by [`HydraulicErosion`](crate::erosion::HydraulicErosion)
+    pub erosion_maps: Option<TerrainErosionMaps>,
+    /// Paths of the rivers near the chunk, see [`RiverNetwork`]
+    pub rivers: Option<Vec<RiverPath>>,
+    /// Lakes found by the last [`LakeDetection`]
+    pub lakes: Option<TerrainLakes>,
+}
+
+pub(crate) use self::egui::register_ui;
+
+/// Ordered list of passes run on the heights of every chunk, after the noise and before the mesh
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub struct TerrainPasses(pub Vec<TerrainPass>);
+
+impl Default for TerrainPasses {
+    fn default() -> Self {
+        Self(vec![
+            TerrainPass {
+                enabled: false,
+                mode: TerrainPassMode::Falloff(TerrainFalloff::Mask(default())),
+            },
+            TerrainPass::new(TerrainPassMode::Erosion(TerrainErosion::default())),
+        ])
+    }
+}
+
+impl TerrainPasses {
+    /// Padding needed by all the enabled passes, each pass reads from the area left valid by the ones before it
+    pub fn padding(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|x| x.enabled)
+            .map(|x| x.mode.padding())
+            .sum()
+    }
+
+    /// Runs every enabled pass in order
+    pub fn apply(&self, map: &mut NoiseMap, context: &TerrainPassContext) -> TerrainPassOutput {
+        let mut output = TerrainPassOutput::default();
+        for (i, pass) in self.0.iter().enumerate().filter(|(_, x)| x.enabled) {
+            let context = TerrainPassContext {
+                earlier_passes: &self.0[..i],
+                ..context.clone()
+            };
+            pass.mode.apply(map, &context, &mut output);
+        }
+        output
+    }
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct TerrainPass {
+    pub enabled: bool,
+    pub mode: TerrainPassMode,
+}
+
+impl Default for TerrainPass {
+    fn default() -> Self {
+        Self::new(TerrainPassMode::default())
+    }
+}
+
+impl TerrainPass {
+    pub fn new(mode: TerrainPassMode) -> Self {
+        Self {
+            enabled: true,
+            mode,
+        }
+    }
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub enum TerrainPassMode {
+    Erosion(TerrainErosion),
+    Terrace(TerraceSteps),
+    Blur(BlurPass),
+    /// Keeps the heights between `min` and `max`
+    Clamp { min: f32, max: f32 },
+    Falloff(TerrainFalloff),
+    Curve(TerrainCurve),
+    Rivers(RiverNetwork),
+    Lakes(LakeDetection),
+    /// User provided [`TerrainHeightPass`]
+    Custom(TerrainCustomPass),
+}
+
+impl Default for TerrainPassMode {
+    fn default() -> Self {
+        TerrainPassMode::Blur(BlurPass::default())
+    }
+}
+
+impl TerrainPassMode {
+    pub fn padding(&self) -> usize {
+        match self {
+            TerrainPassMode::Erosion(x) => x.padding(),
+            TerrainPassMode::Blur(x) => x.radius,
+            TerrainPassMode::Lakes(x) => x.padding,
+            TerrainPassMode::Custom(x) => x.0.padding(),
+            _ => 0,
+        }
+    }
+
+    pub fn apply(
+        &self,
+        map: &mut NoiseMap,
+        context: &TerrainPassContext,
+        output: &mut TerrainPassOutput,
+    ) {
+        match self {
+            TerrainPassMode::Erosion(x) => x.apply(map, context, output),
+            TerrainPassMode::Blur(x) => x.apply(map),
+            TerrainPassMode::Rivers(x) => {
+                let paths = x.apply(map, context);
+                output.rivers.get_or_insert_with(Vec::new).extend(paths);
+            }
+            TerrainPassMode::Lakes(x) => output.lakes = Some(x.apply(map)),
+            TerrainPassMode::Custom(x) => x.0.apply(map, context),
+            TerrainPassMode::Terrace(_)
+            | TerrainPassMode::Clamp { .. }
+            | TerrainPassMode::Falloff(_)
+            | TerrainPassMode::Curve(_) => each_sample(map, |height, x, y| {
+                self.apply_sample(height, context.world_position(x, y), context.seed)
+                    .unwrap_or(height)
+            }),
+        }
+    }
+
+    /// New height of a single sample at a world position, `None` for passes that need the samples around it
+    pub fn apply_sample(&self, height: f32, pos: Vec2, seed: TerrainSeed) -> Option<f32> {
+        match self {
+            TerrainPassMode::Terrace(terrace) => Some(terrace.apply(height, pos, seed)),
+            TerrainPassMode::Clamp { min, max } => Some(height.clamp(*min, *max)),
+            TerrainPassMode::Falloff(falloff) => Some(falloff.apply(height, pos)),
+            TerrainPassMode::Curve(x) => Some(x.get(height)),
+            _ => None,
+        }
+    }
+}
+
+fn each_sample(map: &mut NoiseMap, f: impl Fn(f32, usize, usize) -> f32) {
+    for (x, column) in map.iter_mut().enumerate() {
+        for (y, height) in column.iter_mut().enumerate() {
+            *height = f(*height, x, y);
+        }
+    }
+}
+
+/// Box blur, smooths out noise and erosion artifacts
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct BlurPass {
+    /// Radius in samples
+    #[inspector(min = 1, max = 8, display = NumberDisplay::Slider)]
+    pub radius: usize,
+    /// Blend between the original (0.0) and blurred (1.0) heights
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub strength: f32,
+}
+
+impl Default for BlurPass {
+    fn default() -> Self {
+        Self {
+            radius: 1,
+            strength: 1.0,
+        }
+    }
+}
+
+impl BlurPass {
+    pub fn apply(&self, map: &mut NoiseMap) {
+        let size = map.len();
+        if size == 0 || self.radius == 0 {
+            return;
+        }
+        let radius = self.radius as isize;
+        // samples past the edge of the padded map are clamped, they get cropped off anyway
+        let clamp = |i: isize, len: usize| i.clamp(0, len as isize - 1) as usize;
+
+        // blur along x then y, so it's 2 * radius samples instead of radius squared
+        let mut blurred_x = map.clone();
+        for x in 0..size {
+            for y in 0..map[x].len() {
+                let sum: f32 = (-radius..=radius)
+                    .map(|i| map[clamp(x as isize + i, size)][y])
+                    .sum();
+                blurred_x[x][y] = sum / (2 * radius + 1) as f32;
+            }
+        }
+        for x in 0..size {
+            let len = map[x].len();
+            for y in 0..len {
+                let sum: f32 = (-radius..=radius)
+                    .map(|i| blurred_x[x][clamp(y as isize + i, len)])
+                    .sum();
+                let blurred = sum / (2 * radius + 1) as f32;
+                map[x][y] += (blurred - map[x][y]) * self.strength;
+            }
+        }
+    }
+}
+
+mod egui {
+    use std::any::{Any, TypeId};
+
+    use super::{TerrainPass, TerrainPassMode, TerrainPasses};
+    use crate::egui_helper::many_unimplemented;
+    use bevy::prelude::*;
+    use bevy_inspector_egui::{
+        inspector_egui_impls::InspectorEguiImpl, reflect_inspector::InspectorUi, *,
+    };
+
+    pub fn register_ui(type_registry: &AppTypeRegistry) {
+        let mut type_registry = type_registry.write();
+        type_registry
+            .get_mut(TypeId::of::<TerrainPasses>())
+            .unwrap_or_else(|| panic!("{:?} not registered", std::any::type_name::<TerrainPasses>()))
+            .insert(InspectorEguiImpl::new(
+                passes_ui,
+                passes_ui_readonly,
+                many_unimplemented::<TerrainPasses>,
+            ));
+    }
+
+    fn pass_name(mode: &TerrainPassMode) -> &'static str {
+        match mode {
+            TerrainPassMode::Erosion(_) => "Erosion",
+            TerrainPassMode::Terrace(_) => "Terrace",
+            TerrainPassMode::Blur(_) => "Blur",
+            TerrainPassMode::Clamp { .. } => "Clamp",
+            TerrainPassMode::Falloff(_) => "Falloff",
+            TerrainPassMode::Curve(_) => "Curve",
+            TerrainPassMode::Rivers(_) => "Rivers",
+            TerrainPassMode::Lakes(_) => "Lakes",
+            TerrainPassMode::Custom(_) => "Custom",
+        }
+    }
+
+    /// List of passes that can be toggled, moved up and down, added and removed
+    fn passes_ui(
+        value: &mut dyn Any,
+        ui: &mut egui::Ui,
+        _options: &dyn Any,
+        _id: egui::Id,
+        mut env: InspectorUi<'_, '_>,
+    ) -> bool {
+        let passes = value.downcast_mut::<TerrainPasses>().unwrap();
+        let len = passes.0.len();
+
+        let mut changed = false;
+        let mut move_up = None;
+        let mut remove = None;
+
+        ui.vertical(|ui| {
+            for (i, pass) in passes.0.iter_mut().enumerate() {
+                ui.push_id(i, |ui| {
+                    ui.horizontal(|ui| {
+                        changed |= ui.checkbox(&mut pass.enabled, "").changed();
+                        ui.label(format!("{}: {}", i, pass_name(&pass.mode)));
+                        if ui.add_enabled(i > 0, egui::Button::new("⬆")).clicked() {
+                            move_up = Some(i);
+                        }
+                        if ui.add_enabled(i + 1 < len, egui::Button::new("⬇")).clicked() {
+                            move_up = Some(i + 1);
+                        }
+                        if ui.button("🗑").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+                    ui.collapsing("Settings", |ui| {
+                        changed |= env.ui_for_reflect(&mut pass.mode, ui);
+                    });
+                });
+            }
+            if ui.button("Add").clicked() {
+                passes.0.push(TerrainPass::default());
+                changed = true;
+            }
+        });
+
+        if let Some(i) = move_up {
+            passes.0.swap(i - 1, i);
+            changed = true;
+        }
+        if let Some(i) = remove {
+            passes.0.remove(i);
+            changed = true;
+        }
+        changed
+    }
+
+    fn passes_ui_readonly(
+        value: &dyn Any,
+        ui: &mut egui::Ui,
+        _options: &dyn Any,
+        _id: egui::Id,
+        mut _env: InspectorUi<'_, '_>,
+    ) {
+        let passes = value.downcast_ref::<TerrainPasses>().unwrap();
+        for (i, pass) in passes.0.iter().enumerate() {
+            let state = if pass.enabled { "" } else { " (disabled)" };
+            ui.label(format!("{}: {}{}", i, pass_name(&pass.mode), state));
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use std::{cell::RefCell, collections::BinaryHeap};
+
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+
+use crate::{
+    pass::TerrainPassContext,
+    util::{self, FloodCell, NEIGHBOURS},
+    NoiseMap,
+};
+
+/// Rivers running through or near a chunk, in world space, added to chunk entities when a
+/// [`RiverNetwork`] pass runs. Neighbouring chunks get the same paths where they meet.
+#[derive(Component, Clone, Default)]
+pub struct TerrainRiverPaths(pub Vec<RiverPath>);
+
+/// Polyline from a source down to the sea, a lake, or the river it joins
+#[derive(Clone, Debug, Default)]
+pub struct RiverPath {
+    pub points: Vec<RiverPoint>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RiverPoint {
+    /// World position of the water surface
+    pub position: Vec3,
+    /// Number of sources upstream
+    pub flow: f32,
+    /// Channel width in world units
+    pub width: f32,
+    /// Channel depth below the water in world units
+    pub depth: f32,
+}
+
+/// Traces rivers downhill from sources on a coarse world grid and carves their channels
+///
+/// The rivers follow the heights of the world rather than the padded map, so a river is the same
+/// in every chunk it passes through. Those are the noise with the earlier falloff, curve, clamp and
+/// terrace passes applied, see [`TerrainPassContext::height_at`]. Put it before erosion to have
+/// the channels smoothed out.
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct RiverNetwork {
+    /// Coarse samples per chunk side the rivers are traced on
+    #[inspector(min = 2, max = 64, display = NumberDisplay::Slider)]
+    pub cells_per_chunk: usize,
+    /// Lowest height a river can start at
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub source_height: f32,
+    /// Chance of a river starting in a coarse cell above `source_height`
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub source_chance: f32,
+    /// Rivers end when they get below this height
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub sea_level: f32,
+    /// Longest river in coarse cells, only sources this close are traced for a chunk
+    #[inspector(min = 1, max = 512, display = NumberDisplay::Slider)]
+    pub max_length: usize,
+    /// Largest depression in coarse cells that is filled to let a river through,
+    /// rivers end in a lake in larger ones
+    #[inspector(min = 0, max = 1024, display = NumberDisplay::Slider)]
+    pub max_lake_size: usize,
+    /// Channel width at the source in world units
+    pub width: f32,
+    /// Width added for the square root of the flow
+    pub width_per_flow: f32,
+    /// Channel depth at the source in world units
+    pub depth: f32,
+    /// Depth added for the square root of the flow
+    pub depth_per_flow: f32,
+    /// Width of the banks sloping down to the channel, relative to the channel width
+    #[inspector(min = 0.0, max = 4.0, display = NumberDisplay::Slider)]
+    pub bank_width: f32,
+}
+
+impl Default for RiverNetwork {
+    fn default() -> Self {
+        Self {
+            cells_per_chunk: 16,
+            source_height: 0.6,
+            source_chance: 0.05,
+            sea_level: 0.3,
+            max_length: 64,
+            max_lake_size: 64,
+            width: 4.0,
+            width_per_flow: 2.0,
+            depth: 1.0,
+            depth_per_flow: 0.5,
+            bank_width: 1.0,
+        }
+    }
+}
+
+/// Furthest a channel and its banks reach from the river, in coarse cells
+const MAX_REACH: f32 = 2.0;
+
+impl RiverNetwork {
+    /// Carves the rivers near the chunk into the map and returns their paths
+    pub fn apply(&self, map: &mut NoiseMap, context: &TerrainPassContext) -> Vec<RiverPath> {
+        if map.is_empty() {
+            return vec![];
+        }
+        let grid = CoarseGrid::new(self, context);
+
+        // the padded map, and the cells a channel reaching it can be in
+        let last = map.len() - 1;
+        let area_min = grid.to_cell(context.world_position(0, 0)).floor().as_ivec2();
+        let area_max = grid.to_cell(context.world_position(last, last)).ceil().as_ivec2();
+        let margin = IVec2::splat(MAX_REACH.ceil() as i32 + 2);
+        let (near_min, near_max) = (area_min - margin, area_max + margin);
+
+        let paths = self.trace_rivers(&grid, near_min, near_max, context);
+        self.carve(map, context, &grid, &paths);
+        paths
+    }
+
+    /// Paths of every river that comes within `near_min..=near_max`, sources further away than
+    /// `max_length` can't reach it
+    fn trace_rivers(
+        &self,
+        grid: &CoarseGrid,
+        near_min: IVec2,
+        near_max: IVec2,
+        context: &TerrainPassContext,
+    ) -> Vec<RiverPath> {
+        let reach = IVec2::splat(self.max_length as i32);
+        let (window_min, window_max) = (near_min - reach, near_max + reach);
+        let seed = context.seed.derive("rivers").to_u32();
+
+        // sources in world order, so every chunk traces and joins them the same way
+        let mut traces = vec![];
+        for y in window_min.y..=window_max.y {
+            for x in window_min.x..=window_max.x {
+                let chance = util::hash_to_unit(util::hash_2d(x, y, seed));
+                let cell = IVec2::new(x, y);
+                if chance < self.source_chance && grid.height(cell) >= self.source_height {
+                    traces.push(self.trace(grid, cell));
+                }
+            }
+        }
+
+        // number of rivers through every cell
+        let mut flow: HashMap<IVec2, f32> = HashMap::default();
+        for trace in traces.iter() {
+            let cells: HashSet<IVec2> = trace.iter().map(|(cell, _)| *cell).collect();
+            for cell in cells {
+                *flow.entry(cell).or_default() += 1.0;
+            }
+        }
+
+        // each river stops where it joins one that came before it
+        let mut taken: HashSet<IVec2> = HashSet::default();
+        let mut paths = vec![];
+        for trace in traces.iter() {
+            let mut points = vec![];
+            let mut near = false;
+            for (cell, water) in trace.iter() {
+                let flow = flow[cell];
+                points.push(RiverPoint {
+                    position: grid.world_position(*cell).extend(*water * grid.height_scale).xzy(),
+                    flow,
+                    width: self.width + self.width_per_flow * flow.sqrt(),
+                    depth: self.depth + self.depth_per_flow * flow.sqrt(),
+                });
+                near |= cell.cmpge(near_min).all() && cell.cmple(near_max).all();
+                if !taken.insert(*cell) {
+                    break;
+                }
+            }
+            if near && points.len() > 1 {
+                paths.push(RiverPath { points });
+            }
+        }
+        paths
+    }
+
+    /// Cells and water heights from the source down to the sea or a lake
+    fn trace(&self, grid: &CoarseGrid, source: IVec2) -> Vec<(IVec2, f32)> {
+        // depressions filled so far, so the river doesn't flow back into them
+        let mut filled: HashMap<IVec2, f32> = HashMap::default();
+        let height = |cell: IVec2, filled: &HashMap<IVec2, f32>| {
+            filled.get(&cell).copied().unwrap_or_else(|| grid.height(cell))
+        };
+
+        let mut cells = vec![source];
+        let mut cell = source;
+        while cells.len() < self.max_length && height(cell, &filled) >= self.sea_level {
+            let current = height(cell, &filled);
+
+            // steepest way down
+            let lowest = NEIGHBOURS
+                .iter()
+                .map(|(x, y, distance)| {
+                    let next = cell + IVec2::new(*x, *y);
+                    (next, (current - height(next, &filled)) / distance)
+                })
+                .filter(|(_, slope)| *slope > 0.0)
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+
+            if let Some((next, _)) = lowest {
+                cells.push(next);
+                cell = next;
+                continue;
+            }
+
+            // in a pit, fill it up until the water spills out
+            let Some((route, level, lake)) = self.fill_depression(cell, |x| height(x, &filled))
+            else {
+                break;
+            };
+            for lake_cell in lake {
+                filled.insert(lake_cell, level);
+            }
+            cell = *route.last().unwrap();
+            cells.extend(route);
+        }
+        cells.truncate(self.max_length);
+
+        // water can't be lower than anything downstream, that fills the lakes along the way
+        let mut water = f32::MIN;
+        let mut trace: Vec<(IVec2, f32)> = cells
+            .iter()
+            .rev()
+            .map(|cell| {
+                water = water.max(grid.height(*cell));
+                (*cell, water)
+            })
+            .collect();
+        trace.reverse();
+        trace
+    }
+
+    /// Priority flood from `pit`, returns the cells from the pit to the first one lower than the
+    /// water, the water level and the cells under it, or `None` if the lake gets too big
+    fn fill_depression(
+        &self,
+        pit: IVec2,
+        height: impl Fn(IVec2) -> f32,
+    ) -> Option<(Vec<IVec2>, f32, Vec<IVec2>)> {
+        let mut level = height(pit);
+        let mut parents: HashMap<IVec2, IVec2> = HashMap::default();
+        let mut lake = vec![];
+        let mut queue = BinaryHeap::new();
+        parents.insert(pit, pit);
+        queue.push(FloodCell {
+            height: level,
+            cell: pit,
+        });
+
+        while let Some(FloodCell { height: h, cell }) = queue.pop() {
+            if h < level {
+                let mut route = vec![cell];
+                let mut at = cell;
+                while parents[&at] != pit {
+                    at = parents[&at];
+                    route.push(at);
+                }
+                route.reverse();
+                return Some((route, level, lake));
+            }
+            level = level.max(h);
+            lake.push(cell);
+            if parents.len() > self.max_lake_size {
+                return None;
+            }
+            for (x, y, _) in NEIGHBOURS.iter() {
+                let next = cell + IVec2::new(*x, *y);
+                if !parents.contains_key(&next) {
+                    parents.insert(next, cell);
+                    queue.push(FloodCell {
+                        height: height(next),
+                        cell: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Lowers the map towards the river beds, the lowest of every river is kept so the order doesn't matter
+    fn carve(
+        &self,
+        map: &mut NoiseMap,
+        context: &TerrainPassContext,
+        grid: &CoarseGrid,
+        paths: &[RiverPath],
+    ) {
+        let max_reach = MAX_REACH * grid.cell_size;
+
+        // segments by the cells they can reach
+        let mut segments: Vec<(RiverPoint, RiverPoint)> = vec![];
+        let mut buckets: HashMap<IVec2, Vec<usize>> = HashMap::default();
+        for path in paths.iter() {
+            for pair in path.points.windows(2) {
+                let (a, b) = (pair[0].position.xz(), pair[1].position.xz());
+                let min = grid.to_cell(a.min(b) - max_reach).floor().as_ivec2();
+                let max = grid.to_cell(a.max(b) + max_reach).floor().as_ivec2();
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        buckets.entry(IVec2::new(x, y)).or_default().push(segments.len());
+                    }
+                }
+                segments.push((pair[0], pair[1]));
+            }
+        }
+        if segments.is_empty() {
+            return;
+        }
+
+        for (x, column) in map.iter_mut().enumerate() {
+            for (y, height) in column.iter_mut().enumerate() {
+                let pos = context.world_position(x, y);
+                let Some(nearby) = buckets.get(&grid.to_cell(pos).floor().as_ivec2()) else {
+                    continue;
+                };
+
+                let mut carved = *height;
+                for (a, b) in nearby.iter().map(|i| &segments[*i]) {
+                    let (start, end) = (a.position.xz(), b.position.xz());
+                    let along = end - start;
+                    let t = ((pos - start).dot(along) / along.length_squared()).clamp(0.0, 1.0);
+                    let distance = pos.distance(start + along * t);
+
+                    let half_width = util::lerp(a.width, b.width, t) / 2.0;
+                    let reach = (half_width * (1.0 + self.bank_width)).min(max_reach);
+                    if distance >= reach {
+                        continue;
+                    }
+                    let profile = if distance <= half_width {
+                        1.0
+                    } else {
+                        1.0 - util::smoothstep(half_width, reach, distance)
+                    };
+
+                    let water = util::lerp(a.position.y, b.position.y, t);
+                    let depth = util::lerp(a.depth, b.depth, t);
+                    let bed = (water - depth) / grid.height_scale;
+                    carved = carved.min(util::lerp(*height, height.min(bed), profile));
+                }
+                *height = carved;
+            }
+        }
+    }
+}
+
+/// World-aligned grid the rivers are traced on, heights are taken from [`TerrainPassContext::height_at`]
+struct CoarseGrid<'a> {
+    context: TerrainPassContext<'a>,
+    heights: RefCell<HashMap<IVec2, f32>>,
+    cells_per_chunk: f32,
+    world_scale: f32,
+    /// World units between cells
+    cell_size: f32,
+    /// World units of a height of 1.0
+    height_scale: f32,
+}
+
+impl<'a> CoarseGrid<'a> {
+    fn new(rivers: &RiverNetwork, context: &TerrainPassContext<'a>) -> Self {
+        let cells_per_chunk = rivers.cells_per_chunk.max(1) as f32;
+        Self {
+            context: context.clone(),
+            heights: default(),
+            cells_per_chunk,
+            world_scale: context.world_scale,
+            cell_size: context.world_scale / cells_per_chunk,
+            height_scale: context.height_multiplier * context.world_scale,
+        }
+    }
+
+    /// Laid out like the samples, so the cells line up with the chunks
+    fn world_position(&self, cell: IVec2) -> Vec2 {
+        (cell.as_vec2() / self.cells_per_chunk - 0.5) * self.world_scale
+    }
+
+    fn to_cell(&self, world_pos: Vec2) -> Vec2 {
+        (world_pos / self.world_scale + 0.5) * self.cells_per_chunk
+    }
+
+    fn height(&self, cell: IVec2) -> f32 {
+        *self
+            .heights
+            .borrow_mut()
+            .entry(cell)
+            .or_insert_with(|| self.context.height_at(self.world_position(cell)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::TerrainGenerator;
+
+    #[test]
+    fn fill_depression_spills_over_the_lowest_rim() {
+        let rivers = RiverNetwork::default();
+        // a pit in a ring of 1.0 with a gap of 0.8, everything outside the ring is lower
+        let height = |cell: IVec2| match (cell.x.abs().max(cell.y.abs()), cell) {
+            (0, _) => 0.0,
+            (1, IVec2 { x: 1, y: 0 }) => 0.8,
+            (1, _) => 1.0,
+            _ => 0.5,
+        };
+        let (route, level, lake) = rivers.fill_depression(IVec2::ZERO, height).unwrap();
+        assert_eq!(level, 0.8);
+        assert_eq!(route[0], IVec2::new(1, 0));
+        assert!(height(*route.last().unwrap()) < level);
+        assert!(lake.contains(&IVec2::ZERO) && lake.contains(&IVec2::new(1, 0)));
+    }
+
+    #[test]
+    fn fill_depression_gives_up_on_large_lakes() {
+        let rivers = RiverNetwork {
+            max_lake_size: 8,
+            ..default()
+        };
+        let bowl = |cell: IVec2| cell.as_vec2().length();
+        assert!(rivers.fill_depression(IVec2::ZERO, bowl).is_none());
+    }
+
+    #[test]
+    fn trace_flows_downhill() {
+        let rivers = RiverNetwork::default();
+        let generator = TerrainGenerator::default();
+        let context = generator.pass_context(IVec2::ZERO, 0);
+        let grid = CoarseGrid::new(&rivers, &context);
+
+        let cells = (0..16).flat_map(|x| (0..16).map(move |y| IVec2::new(x, y)));
+        let source = cells
+            .max_by(|a, b| grid.height(*a).total_cmp(&grid.height(*b)))
+            .unwrap();
+        let trace = rivers.trace(&grid, source);
+
+        assert!(trace.len() > 1);
+        assert!(grid.height(trace.last().unwrap().0) < grid.height(source));
+        for pair in trace.windows(2) {
+            let ((a, water_a), (b, water_b)) = (pair[0], pair[1]);
+            assert!(
+                (a - b).abs().max_element() == 1,
+                "{a} and {b} aren't neighbours"
+            );
+            assert!(water_b <= water_a);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+/// Seed for the terrain, hashed with a fixed hash so the same seed gives the same terrain
+/// on every run and machine.
+///
+/// Every layer of the terrain derives its own seed with [`TerrainSeed::derive`] or
+/// [`TerrainSeed::layer`], so nearby seeds still give unrelated worlds.
+/// ```ignore
+/// generator.set_seed("my world");
+/// generator.set_seed(1234);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Reflect)]
+pub struct TerrainSeed(pub u64);
+
+impl TerrainSeed {
+    /// Hashes a seed typed by a player, numbers are used as is so `"1234"` is the same as `1234`
+    pub fn from_str_seed(seed: &str) -> Self {
+        let seed = seed.trim();
+        match seed.parse::<u64>() {
+            Ok(x) => Self(x),
+            Err(_) => Self(mix(fnv1a(seed.as_bytes()))),
+        }
+    }
+
+    /// Independent seed for a purpose, e.g. `"erosion"` or `"moisture"`
+    pub fn derive(self, purpose: &str) -> Self {
+        Self(mix(self.0 ^ fnv1a(purpose.as_bytes())))
+    }
+
+    /// Independent seed for a numbered layer, e.g. an octave or a graph node
+    pub fn layer(self, index: u64) -> Self {
+        Self(mix(self.0 ^ mix(index.wrapping_add(0x9e37_79b9_7f4a_7c15))))
+    }
+
+    /// Seed for `noisy_bevy`, which takes an `f32`, kept in a small range so it stays precise
+    pub fn noise_seed(self) -> f32 {
+        // use the high bits, the hash is best mixed there
+        (mix(self.0) >> 40) as f32 / (1u64 << 24) as f32 * 1000.0
+    }
+
+    /// Seed for 32 bit hashes, like [`util::hash_2d`](crate::util::hash_2d)
+    pub fn to_u32(self) -> u32 {
+        let x = mix(self.0);
+        (x ^ (x >> 32)) as u32
+    }
+}
+
+impl From<u64> for TerrainSeed {
+    fn from(seed: u64) -> Self {
+        Self(seed)
+    }
+}
+
+impl From<&str> for TerrainSeed {
+    fn from(seed: &str) -> Self {
+        Self::from_str_seed(seed)
+    }
+}
+
+impl From<String> for TerrainSeed {
+    fn from(seed: String) -> Self {
+        Self::from_str_seed(&seed)
+    }
+}
+
+impl From<TerrainSeed> for u64 {
+    fn from(seed: TerrainSeed) -> Self {
+        seed.0
+    }
+}
+
+impl fmt::Display for TerrainSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 64 bit FNV-1a, unlike `DefaultHasher` it's guaranteed to never change
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// SplitMix64 finalizer, spreads small differences in the input over every bit
+fn mix(x: u64) -> u64 {
+    let mut x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
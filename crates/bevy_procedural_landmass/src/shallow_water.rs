@@ -0,0 +1,262 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+
+use crate::{pass::TerrainPassContext, NoiseMap};
+
+/// Water depth of every sample left by [`ShallowWaterErosion`], in the same units as the heights,
+/// added to chunk entities when the erosion runs
+#[derive(Component, Clone, Default)]
+pub struct TerrainWaterMap(pub NoiseMap);
+
+/// Grid based hydraulic erosion, simulates rain flowing between samples through virtual pipes
+/// and carrying sediment with it. Gives connected river channels and valleys, and leaves
+/// water standing in the low areas.
+///
+/// Heights are simulated in sample units, so the result respects `height_multiplier` and `world_scale`.
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct ShallowWaterErosion {
+    #[inspector(min = 1, max = 500, display = NumberDisplay::Slider)]
+    pub iterations: usize,
+    #[inspector(min = 0.001, max = 0.5, display = NumberDisplay::Slider)]
+    pub time_step: f32,
+    /// Water added to every sample each iteration
+    #[inspector(min = 0.0, max = 0.1, display = NumberDisplay::Slider)]
+    pub rain_rate: f32,
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub evaporation_rate: f32,
+    /// How much sediment the water can carry relative to its speed and the slope
+    #[inspector(min = 0.0, max = 5.0, display = NumberDisplay::Slider)]
+    pub sediment_capacity: f32,
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub dissolve_rate: f32,
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub deposit_rate: f32,
+    /// Lowest slope used for the sediment capacity, so flat water still erodes a little
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub min_slope: f32,
+    pub gravity: f32,
+}
+
+impl Default for ShallowWaterErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 30,
+            time_step: 0.05,
+            rain_rate: 0.02,
+            evaporation_rate: 0.02,
+            sediment_capacity: 0.5,
+            dissolve_rate: 0.1,
+            deposit_rate: 0.1,
+            min_slope: 0.05,
+            gravity: 9.81,
+        }
+    }
+}
+
+/// Outflow through the pipes of a sample
+#[derive(Clone, Copy, Default)]
+struct Flux {
+    left: f32,
+    right: f32,
+    down: f32,
+    up: f32,
+}
+
+impl Flux {
+    fn sum(&self) -> f32 {
+        self.left + self.right + self.down + self.up
+    }
+}
+
+impl ShallowWaterErosion {
+    /// Every step of an iteration reads its neighbours, so each iteration needs a few more samples of border
+    pub fn padding(&self) -> usize {
+        self.iterations * 4 + 1
+    }
+
+    /// Erodes the map and returns the depth of the water left on it
+    pub fn erode(&self, map: &mut NoiseMap, context: &TerrainPassContext) -> NoiseMap {
+        let size = map.len();
+        // heights in samples, so the slopes are the same as in the world
+        let scale = context.height_multiplier * context.world_scale / context.texel_size;
+        if size < 3 || scale <= 0.0 {
+            return vec![vec![0.0; size]; size];
+        }
+
+        let dt = self.time_step;
+        let mut terrain: NoiseMap = map
+            .iter()
+            .map(|column| column.iter().map(|h| h * scale).collect())
+            .collect();
+        let mut water = vec![vec![0f32; size]; size];
+        let mut sediment = vec![vec![0f32; size]; size];
+        let mut flux = vec![vec![Flux::default(); size]; size];
+        let mut velocity = vec![vec![Vec2::ZERO; size]; size];
+
+        for _ in 0..self.iterations {
+            // rain
+            for column in water.iter_mut() {
+                for depth in column.iter_mut() {
+                    *depth += self.rain_rate * dt;
+                }
+            }
+
+            // outflow from the difference in water surface to each neighbour, no flow over the map edge
+            let mut next_flux = flux.clone();
+            for x in 0..size {
+                for y in 0..size {
+                    let surface = terrain[x][y] + water[x][y];
+                    let pipe = |old: f32, nx: isize, ny: isize| {
+                        if nx < 0 || ny < 0 || nx >= size as isize || ny >= size as isize {
+                            return 0.0;
+                        }
+                        let (nx, ny) = (nx as usize, ny as usize);
+                        let difference = surface - terrain[nx][ny] - water[nx][ny];
+                        (old + dt * self.gravity * difference).max(0.0)
+                    };
+                    let (ix, iy) = (x as isize, y as isize);
+                    let old = flux[x][y];
+                    let mut f = Flux {
+                        left: pipe(old.left, ix - 1, iy),
+                        right: pipe(old.right, ix + 1, iy),
+                        down: pipe(old.down, ix, iy - 1),
+                        up: pipe(old.up, ix, iy + 1),
+                    };
+                    // can't send more water than the sample has
+                    let total = f.sum() * dt;
+                    if total > water[x][y] && total > 0.0 {
+                        let k = water[x][y] / total;
+                        f.left *= k;
+                        f.right *= k;
+                        f.down *= k;
+                        f.up *= k;
+                    }
+                    next_flux[x][y] = f;
+                }
+            }
+            flux = next_flux;
+
+            // move the water and find how fast it's flowing
+            let mut next_water = water.clone();
+            for x in 0..size {
+                for y in 0..size {
+                    let f = flux[x][y];
+                    let from_left = if x > 0 { flux[x - 1][y].right } else { 0.0 };
+                    let from_right = if x + 1 < size { flux[x + 1][y].left } else { 0.0 };
+                    let from_down = if y > 0 { flux[x][y - 1].up } else { 0.0 };
+                    let from_up = if y + 1 < size { flux[x][y + 1].down } else { 0.0 };
+
+                    let inflow = from_left + from_right + from_down + from_up;
+                    let depth = (water[x][y] + dt * (inflow - f.sum())).max(0.0);
+                    let average_depth = (water[x][y] + depth) / 2.0;
+
+                    velocity[x][y] = if average_depth > 1e-4 {
+                        Vec2::new(
+                            (from_left - f.left + f.right - from_right) / 2.0,
+                            (from_down - f.down + f.up - from_up) / 2.0,
+                        ) / average_depth
+                    } else {
+                        Vec2::ZERO
+                    };
+                    next_water[x][y] = depth;
+                }
+            }
+            water = next_water;
+
+            // erode or deposit depending on how much sediment the water can carry
+            let mut next_terrain = terrain.clone();
+            for x in 0..size {
+                for y in 0..size {
+                    let left = terrain[x.saturating_sub(1)][y];
+                    let right = terrain[(x + 1).min(size - 1)][y];
+                    let down = terrain[x][y.saturating_sub(1)];
+                    let up = terrain[x][(y + 1).min(size - 1)];
+                    let gradient = Vec2::new(right - left, up - down) / 2.0;
+                    let slope = (gradient.length() / (1.0 + gradient.length_squared()).sqrt())
+                        .max(self.min_slope);
+
+                    // deeper and faster water carries more
+                    let capacity =
+                        self.sediment_capacity * slope * velocity[x][y].length() * water[x][y];
+                    let carried = sediment[x][y];
+                    if capacity > carried {
+                        let amount = self.dissolve_rate * dt * (capacity - carried);
+                        next_terrain[x][y] -= amount;
+                        sediment[x][y] += amount;
+                    } else {
+                        let amount = self.deposit_rate * dt * (carried - capacity);
+                        next_terrain[x][y] += amount;
+                        sediment[x][y] -= amount;
+                    }
+                }
+            }
+            terrain = next_terrain;
+
+            // carry the sediment with the water, looking back at most one sample
+            let mut next_sediment = sediment.clone();
+            for x in 0..size {
+                for y in 0..size {
+                    let back = -(velocity[x][y] * dt).clamp(Vec2::NEG_ONE, Vec2::ONE);
+                    next_sediment[x][y] = sample_bilinear(&sediment, x, y, back);
+                }
+            }
+            sediment = next_sediment;
+
+            // evaporation
+            for column in water.iter_mut() {
+                for depth in column.iter_mut() {
+                    *depth *= 1.0 - self.evaporation_rate * dt;
+                }
+            }
+        }
+
+        for (x, column) in map.iter_mut().enumerate() {
+            for (y, height) in column.iter_mut().enumerate() {
+                *height = terrain[x][y] / scale;
+                water[x][y] /= scale;
+            }
+        }
+        water
+    }
+}
+
+/// Bilinear sample at an offset from a sample, clamped to the edge of the map
+///
+/// The offset is kept apart from the sample index, so the result doesn't depend on where
+/// the sample is in the map and chunks sharing a sample get the same value.
+fn sample_bilinear(map: &NoiseMap, x: usize, y: usize, offset: Vec2) -> f32 {
+    let cell = offset.floor();
+    let t = offset - cell;
+    let max = map.len() as isize - 1;
+    let index = |i: usize, o: f32| (i as isize + o as isize).clamp(0, max) as usize;
+    let (x0, y0) = (index(x, cell.x), index(y, cell.y));
+    let (x1, y1) = (index(x, cell.x + 1.0), index(y, cell.y + 1.0));
+
+    let bottom = map[x0][y0] + (map[x1][y0] - map[x0][y0]) * t.x;
+    let top = map[x0][y1] + (map[x1][y1] - map[x0][y1]) * t.x;
+    bottom + (top - bottom) * t.y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::TerrainGenerator;
+
+    #[test]
+    fn erodes_a_slope_and_leaves_water() {
+        let generator = TerrainGenerator {
+            chunk_size: 16,
+            ..default()
+        };
+        let mut map: NoiseMap = (0..17).map(|x| vec![0.2 + x as f32 * 0.02; 17]).collect();
+        let original = map.clone();
+
+        let context = generator.pass_context(IVec2::ZERO, 0);
+        let water = ShallowWaterErosion::default().erode(&mut map, &context);
+        assert_ne!(map, original);
+        assert!(map.iter().flatten().all(|h| h.is_finite()));
+        assert!(water.iter().flatten().any(|depth| *depth > 0.0));
+        assert!(water.iter().flatten().all(|depth| *depth >= 0.0));
+    }
+}
@@ -0,0 +1,28 @@
+use bevy::prelude::*;
+
+use crate::{seed::TerrainSeed, util};
+
+/// User defined height function, plug it into [`TerrainNoiseMode::Custom`](crate::noise::TerrainNoiseMode::Custom)
+/// to use it in place of the built in noise.
+///
+/// Sources are sampled from the chunk tasks, so they need to be `Send + Sync` and cheap to clone.
+pub trait TerrainHeightSource: TerrainHeightSourceClone + Send + Sync + 'static {
+    /// Returns the height at a world-space position, expected to be in the 0..1 range
+    fn sample(&self, pos: Vec2, seed: TerrainSeed) -> f32;
+}
+
+util::boxed_trait!(
+    /// A [`TerrainHeightSource`] stored in the noise settings, shows up as an opaque value in the inspector
+    TerrainCustomSource(dyn TerrainHeightSource),
+    TerrainHeightSourceClone
+);
+
+impl TerrainCustomSource {
+    pub fn new(source: impl TerrainHeightSource) -> Self {
+        Self(Box::new(source))
+    }
+
+    pub fn sample(&self, pos: Vec2, seed: TerrainSeed) -> f32 {
+        self.0.sample(pos, seed)
+    }
+}
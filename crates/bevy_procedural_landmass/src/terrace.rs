@@ -0,0 +1,78 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+use noisy_bevy::simplex_noise_2d_seeded;
+
+use crate::{seed::TerrainSeed, util};
+
+/// Quantises the heights into steps, for paddy hills, canyon walls and mesas
+#[derive(Clone, Default, Reflect, InspectorOptions)]
+#[reflect(InspectorOptions)]
+pub enum TerrainTerrace {
+    #[default]
+    None,
+    Steps(TerraceSteps),
+}
+
+impl TerrainTerrace {
+    pub fn apply(&self, height: f32, world_pos: Vec2, seed: TerrainSeed) -> f32 {
+        match self {
+            TerrainTerrace::None => height,
+            TerrainTerrace::Steps(x) => x.apply(height, world_pos, seed),
+        }
+    }
+}
+
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct TerraceSteps {
+    /// Number of steps between 0.0 and 1.0
+    #[inspector(min = 1, max = 64, display = NumberDisplay::Slider)]
+    pub steps: usize,
+    /// 0.0 gives flat steps with vertical walls, 1.0 blends the walls over the whole step
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub smoothness: f32,
+    /// How far the step heights are moved by noise, in steps
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub jitter: f32,
+    /// Size of the jitter noise in world units
+    #[inspector(min = 1.0, max = 5000.0, display = NumberDisplay::Slider)]
+    pub jitter_scale: f32,
+}
+
+impl Default for TerraceSteps {
+    fn default() -> Self {
+        Self {
+            steps: 8,
+            smoothness: 0.2,
+            jitter: 0.0,
+            jitter_scale: 500.0,
+        }
+    }
+}
+
+impl TerraceSteps {
+    pub fn apply(&self, height: f32, world_pos: Vec2, seed: TerrainSeed) -> f32 {
+        let steps = self.steps.max(1) as f32;
+
+        // shift the steps up and down across the world, so the terraces don't all line up
+        let shift = if self.jitter > 0.0 {
+            let noise = simplex_noise_2d_seeded(
+                world_pos / self.jitter_scale,
+                seed.derive("terrace").noise_seed(),
+            );
+            noise * 0.5 * self.jitter
+        } else {
+            0.0
+        };
+
+        let t = height * steps + shift;
+        let step = t.floor();
+        // flat until the wall at the end of the step
+        let wall = if self.smoothness > 0.0 {
+            util::smoothstep(1.0 - self.smoothness, 1.0, t - step)
+        } else {
+            0.0
+        };
+        ((step + wall - shift) / steps).clamp(0.0, 1.0)
+    }
+}
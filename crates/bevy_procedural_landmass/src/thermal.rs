@@ -0,0 +1,102 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{inspector_options::std_options::NumberDisplay, prelude::*};
+
+use crate::{
+    pass::TerrainPassContext,
+    util::{self, NEIGHBOURS},
+    NoiseMap,
+};
+
+/// Moves material downhill wherever the slope is steeper than the talus angle, gives scree slopes
+///
+/// Add it as another erosion pass before or after hydraulic erosion to chain them.
+#[derive(Clone, Reflect, InspectorOptions)]
+#[reflect(Default, InspectorOptions)]
+pub struct ThermalErosion {
+    /// Steepest slope in degrees that material can rest on
+    #[inspector(min = 0.0, max = 89.0, display = NumberDisplay::Slider)]
+    pub talus_angle: f32,
+    #[inspector(min = 1, max = 200, display = NumberDisplay::Slider)]
+    pub iterations: usize,
+    /// Fraction of the material above the talus angle moved each iteration
+    #[inspector(min = 0.0, max = 1.0, display = NumberDisplay::Slider)]
+    pub transfer_rate: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            talus_angle: 35.0,
+            iterations: 20,
+            transfer_rate: 0.5,
+        }
+    }
+}
+
+impl ThermalErosion {
+    /// A sample takes material from its neighbours based on their own neighbours, so every iteration
+    /// depends on the heights two samples out, that is the border needed to match the neighbouring chunks
+    pub fn padding(&self) -> usize {
+        2 * self.iterations + 1
+    }
+
+    /// Height difference in the map's 0.0 - 1.0 units over one sample at the talus angle
+    pub fn talus_height(&self, context: &TerrainPassContext) -> f32 {
+        let world_height = context.height_multiplier * context.world_scale;
+        if world_height <= 0.0 {
+            return f32::MAX;
+        }
+        self.talus_angle.to_radians().tan() * context.texel_size / world_height
+    }
+
+    pub fn erode(&self, map: &mut NoiseMap, context: &TerrainPassContext) {
+        let size = map.len();
+        let talus = self.talus_height(context);
+        let mut delta = vec![vec![0f32; size]; size];
+
+        for _ in 0..self.iterations {
+            // every sample reads the heights from the last iteration, so the order doesn't matter
+            for x in 0..size {
+                for y in 0..size {
+                    let height = map[x][y];
+                    let mut excess = [0f32; 8];
+                    let mut total = 0.0;
+                    let mut max_excess = 0f32;
+
+                    for (i, (dx, dy, distance)) in NEIGHBOURS.iter().enumerate() {
+                        let Some((nx, ny)) = util::neighbour(x, y, *dx, *dy, size) else {
+                            continue;
+                        };
+                        let over = height - map[nx][ny] - talus * distance;
+                        if over > 0.0 {
+                            excess[i] = over;
+                            total += over;
+                            max_excess = max_excess.max(over);
+                        }
+                    }
+
+                    if total <= 0.0 {
+                        continue;
+                    }
+
+                    // move half the steepest excess at most, so the slope settles instead of flipping
+                    let amount = max_excess * 0.5 * self.transfer_rate;
+                    delta[x][y] -= amount;
+                    for (i, (dx, dy, _)) in NEIGHBOURS.iter().enumerate() {
+                        if excess[i] > 0.0 {
+                            let (nx, ny) = util::neighbour(x, y, *dx, *dy, size).unwrap();
+                            delta[nx][ny] += amount * excess[i] / total;
+                        }
+                    }
+                }
+            }
+
+            for (column, delta) in map.iter_mut().zip(delta.iter_mut()) {
+                for (height, delta) in column.iter_mut().zip(delta.iter_mut()) {
+                    *height += *delta;
+                    *delta = 0.0;
+                }
+            }
+        }
+    }
+}
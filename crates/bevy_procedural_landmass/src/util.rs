@@ -1,4 +1,6 @@
-use bevy::prelude::Color;
+use std::cmp::Ordering;
+
+use bevy::prelude::{Color, IVec2, Vec2};
 
 /// Remaps a value from one range to another range.
 pub(crate) fn remap(value: f32, original_min: f32, original_max: f32, target_min: f32, target_max: f32) -> f32 {
@@ -9,6 +11,65 @@ pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a * (1.0 - t) + b * t
 }
 
+/// Smooth Hermite interpolation between 0 and 1 as `value` goes from `edge0` to `edge1`
+pub fn smoothstep(edge0: f32, edge1: f32, value: f32) -> f32 {
+    let t = ((value - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// World-space position of a sample counted from the world origin, worked out from the whole
+/// sample index so every chunk gets the exact same position for the samples they share
+pub(crate) fn texel_world_position(texel: IVec2, chunk_size: usize, world_scale: f32) -> Vec2 {
+    (texel.as_vec2() / chunk_size as f32 - 0.5) * world_scale
+}
+
+/// Hashes a 2d integer coordinate with a seed, used to place random points in a grid
+pub fn hash_2d(x: i32, y: i32, seed: u32) -> u32 {
+    let mut h = seed ^ 0x9e37_79b9;
+    h ^= (x as u32).wrapping_mul(0x85eb_ca6b);
+    h = h.rotate_left(13).wrapping_mul(0xc2b2_ae35);
+    h ^= (y as u32).wrapping_mul(0x27d4_eb2f);
+    h = h.rotate_left(17).wrapping_mul(0x1656_67b1);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h
+}
+
+/// Maps a hash to the 0..1 range
+pub fn hash_to_unit(hash: u32) -> f32 {
+    (hash >> 8) as f32 / (1u32 << 24) as f32
+}
+
+/// Declares a cloneable box for a user defined trait object, `$wrapper(pub Box<dyn $inner>)`, reflected as an
+/// opaque value, along with the `$clone` super trait that clones it, implemented for every `$inner` that is [`Clone`]
+macro_rules! boxed_trait {
+    ($(#[$meta:meta])* $wrapper:ident(dyn $inner:ident), $clone:ident) => {
+        #[doc = concat!("Lets [`", stringify!($inner), "`] be cloned out of a [`", stringify!($wrapper), "`], implemented for every one that is [`Clone`].")]
+        pub trait $clone {
+            fn clone_box(&self) -> Box<dyn $inner>;
+        }
+
+        impl<T: $inner + Clone> $clone for T {
+            fn clone_box(&self) -> Box<dyn $inner> {
+                Box::new(self.clone())
+            }
+        }
+
+        $(#[$meta])*
+        #[derive(bevy::reflect::Reflect)]
+        #[reflect_value]
+        pub struct $wrapper(pub Box<dyn $inner>);
+
+        impl Clone for $wrapper {
+            fn clone(&self) -> Self {
+                Self(self.0.clone_box())
+            }
+        }
+    };
+}
+pub(crate) use boxed_trait;
+
 #[allow(dead_code)]
 pub(crate) fn lerp_color( a: Color, b: Color, t: f32) -> Color {
     Color::rgb(
@@ -16,4 +77,52 @@ pub(crate) fn lerp_color( a: Color, b: Color, t: f32) -> Color {
         lerp(a.g(), b.g(), t),
         lerp(a.b(), b.b(), t),
     )
-}
\ No newline at end of file
+}
+
+/// Offsets of the eight samples around a sample, with their distance in samples
+pub(crate) const NEIGHBOURS: [(i32, i32, f32); 8] = [
+    (-1, -1, std::f32::consts::SQRT_2),
+    (0, -1, 1.0),
+    (1, -1, std::f32::consts::SQRT_2),
+    (-1, 0, 1.0),
+    (1, 0, 1.0),
+    (-1, 1, std::f32::consts::SQRT_2),
+    (0, 1, 1.0),
+    (1, 1, std::f32::consts::SQRT_2),
+];
+
+/// Sample at an offset from `x`, `y` in a map `size` samples across, `None` past the edge
+pub(crate) fn neighbour(x: usize, y: usize, dx: i32, dy: i32, size: usize) -> Option<(usize, usize)> {
+    let nx = x.checked_add_signed(dx as isize)?;
+    let ny = y.checked_add_signed(dy as isize)?;
+    (nx < size && ny < size).then_some((nx, ny))
+}
+
+/// Lowest height first, ties broken by position so the flood is the same every time
+pub(crate) struct FloodCell {
+    pub height: f32,
+    pub cell: IVec2,
+}
+
+impl Ord for FloodCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .height
+            .total_cmp(&self.height)
+            .then_with(|| (other.cell.y, other.cell.x).cmp(&(self.cell.y, self.cell.x)))
+    }
+}
+
+impl PartialOrd for FloodCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FloodCell {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FloodCell {}